@@ -1,4 +1,11 @@
+use futures::Future;
+use futures::sync::oneshot;
 use serde::Deserialize;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::thread;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Account {
@@ -6,4 +13,167 @@ pub struct Account {
     pub server: Option<String>,
     pub port: Option<u16>,
     pub autoconnect: bool,
+    /// Priority of our presences, 0 when unset
+    pub priority: Option<i8>,
+    /// Nick used in channels, the JID node when unset
+    pub nick: Option<String>,
+    /// Number of messages of channel history to receive when joining
+    pub history: Option<u32>,
+    pub password_command: Option<String>,
+    pub secrets_file: Option<PathBuf>,
+}
+
+impl Account {
+    /// Password from the configured credential source, None when the user has to type it. The
+    /// password command may prompt on the terminal, so this must run before the UI takes it over.
+    pub fn password(&self) -> Result<Option<String>, String> {
+        self.resolve_password(true)
+    }
+
+    /// Same as `password` once the UI is running: the command gets no terminal and runs in its own
+    /// thread, not to block the event loop while it waits for a slow command or a pinentry
+    pub fn password_in_background(&self) -> impl Future<Item = Option<String>, Error = String> {
+        let (sender, receiver) = oneshot::channel();
+        let account = self.clone();
+        thread::spawn(move || {
+            let _ = sender.send(account.resolve_password(false));
+        });
+
+        receiver.map_err(|_| "Password command was interrupted".to_string()).and_then(|result| result)
+    }
+
+    fn resolve_password(&self, terminal: bool) -> Result<Option<String>, String> {
+        if let Some(command) = &self.password_command {
+            return password_from_command(command, terminal).map(Some);
+        }
+
+        if let Some(path) = &self.secrets_file {
+            return password_from_secrets_file(path, &self.login).map(Some);
+        }
+
+        Ok(None)
+    }
+}
+
+/// Run the command through the shell, its first line of output is the password
+fn password_from_command(command: &str, terminal: bool) -> Result<String, String> {
+    let mut process = process::Command::new("sh");
+    process.arg("-c").arg(command);
+    if !terminal {
+        // The terminal is in raw mode and drawn by the UI, anything read or written there breaks it
+        process.stdin(Stdio::null()).stderr(Stdio::null());
+    }
+
+    let output = match process.output() {
+        Ok(output) => output,
+        Err(err) => return Err(format!("Cannot run password command: {}", err)),
+    };
+
+    if !output.status.success() {
+        return Err(format!("Password command failed: {}", output.status));
+    }
+
+    match String::from_utf8(output.stdout) {
+        Ok(stdout) => match stdout.lines().next() {
+            Some(password) if !password.is_empty() => Ok(password.to_string()),
+            _ => Err("Password command returned nothing".to_string()),
+        },
+        Err(_) => Err("Password command returned invalid utf-8".to_string()),
+    }
+}
+
+/// Look the password up in a netrc-style file, refusing files readable by group or others
+fn password_from_secrets_file(path: &Path, login: &str) -> Result<String, String> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) => return Err(format!("Cannot read secrets file {}: {}", path.display(), err)),
+    };
+
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return Err(format!("Secrets file {} must not be accessible by group or others", path.display()));
+    }
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => return Err(format!("Cannot read secrets file {}: {}", path.display(), err)),
+    };
+
+    match find_secret(&content, login) {
+        Some(password) => Ok(password),
+        None => Err(format!("No password for {} in {}", login, path.display())),
+    }
+}
+
+#[derive(Default)]
+struct Secret {
+    default: bool,
+    machine: Option<String>,
+    login: Option<String>,
+    password: Option<String>,
+}
+
+/// Entries are either `machine <jid> password <password>` or `machine <domain> login <node>
+/// password <password>`. A `default` entry applies to any account.
+fn find_secret(content: &str, login: &str) -> Option<String> {
+    let bare = login.split('/').next().unwrap();
+    let (node, domain) = match bare.find('@') {
+        Some(index) => (&bare[..index], &bare[index + 1..]),
+        None => ("", bare),
+    };
+
+    let mut secrets = Vec::new();
+    let mut tokens = content.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => secrets.push(Secret { machine: tokens.next().map(str::to_string), ..Default::default() }),
+            "default" => secrets.push(Secret { default: true, ..Default::default() }),
+            "login" => if let Some(secret) = secrets.last_mut() {
+                secret.login = tokens.next().map(str::to_string);
+            },
+            "password" => if let Some(secret) = secrets.last_mut() {
+                secret.password = tokens.next().map(str::to_string);
+            },
+            _ => {},
+        }
+    }
+
+    let matches = |secret: &&Secret| {
+        match (secret.machine.as_ref(), secret.login.as_ref()) {
+            (Some(machine), None) => machine == bare,
+            (Some(machine), Some(login)) => machine == domain && (login == node || login == bare),
+            (None, _) => false,
+        }
+    };
+
+    secrets.iter().find(matches)
+        .or_else(|| secrets.iter().find(|secret| secret.default))
+        .and_then(|secret| secret.password.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_secret_by_jid() {
+        let content = "machine other@server.tld password other\nmachine me@server.tld password secret\n";
+
+        assert_eq!(find_secret(content, "me@server.tld"), Some("secret".to_string()));
+        assert_eq!(find_secret(content, "me@server.tld/aparte"), Some("secret".to_string()));
+    }
+
+    #[test]
+    fn test_find_secret_by_domain_and_login() {
+        let content = "machine server.tld login other password other machine server.tld login me password secret";
+
+        assert_eq!(find_secret(content, "me@server.tld"), Some("secret".to_string()));
+    }
+
+    #[test]
+    fn test_find_secret_falls_back_to_default() {
+        let content = "machine server.tld login other password other\ndefault password fallback\n";
+
+        assert_eq!(find_secret(content, "me@server.tld"), Some("fallback".to_string()));
+        assert_eq!(find_secret("machine server.tld login other password other", "me@server.tld"), None);
+    }
 }
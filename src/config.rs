@@ -6,4 +6,16 @@ use crate::account::Account;
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub accounts: HashMap<String, Account>,
+    pub auto_away: Option<AutoAway>,
+}
+
+/// Switch to away, then xa, after some time without any keypress
+#[derive(Debug, Clone, Deserialize)]
+pub struct AutoAway {
+    /// Seconds of inactivity before going away
+    pub away: u64,
+    /// Seconds of inactivity before going xa
+    pub xa: Option<u64>,
+    /// Status message while idle, the current one is kept when unset
+    pub message: Option<String>,
 }
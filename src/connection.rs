@@ -0,0 +1,387 @@
+use futures::{future, Future, Stream};
+use futures::future::{Either, Loop};
+use sasl::client::Mechanism;
+use sasl::client::mechanisms::{Plain, Scram};
+use sasl::common::{ChannelBinding, Credentials};
+use sasl::common::scram::{Sha1, Sha256};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tokio::net::TcpStream;
+use tokio_tls::{TlsConnector, TlsStream};
+use tokio_xmpp::{AuthError, ConnecterError, Error, Packet, ProtocolError, StartTlsClient};
+use tokio_xmpp::xmpp_stream::XMPPStream;
+use trust_dns_resolver::AsyncResolver;
+use xmpp_parsers::{Element, FullJid, ns, sm};
+use xmpp_parsers::bind::{BindQuery, BindResponse};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::sasl::{Auth, Challenge, Failure, Mechanism as SaslMechanism, Response, Success};
+
+pub type XmppStream = XMPPStream<TlsStream<TcpStream>>;
+
+const NS_XMPP_TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+const BIND_ID: &str = "resource-bind";
+const DEFAULT_PORT: u16 = 5222;
+const DIRECT_TLS_PORT: u16 = 5223;
+
+/// Outcome of the stream negotiation
+pub enum Session {
+    /// A fresh session was bound to `jid`, which the server may have chosen instead of the
+    /// requested one. `stream_management` tells if the server supports XEP-0198
+    New { jid: FullJid, stream_management: bool },
+    /// The previous session was resumed, with the count of our stanzas the server handled
+    Resumed(u32),
+}
+
+/// Connect, authenticate and either resume the given stream management session or bind a new
+/// one. tokio_xmpp::Client always binds and hides the stream features, and its SRV lookup and SASL
+/// negotiation aren't public, so the negotiation is done here from tokio_xmpp building blocks. It
+/// follows tokio_xmpp's own: same mechanisms in the same order of preference, and no channel
+/// binding as native-tls doesn't expose the tls-unique data SCRAM-PLUS needs.
+///
+/// `server` and `port` override the SRV lookup, connecting to port 5223 uses direct TLS instead
+/// of STARTTLS.
+pub fn connect(account: FullJid, server: Option<String>, port: Option<u16>, password: String, resume: Option<(String, u32)>) -> impl Future<Item = (XmppStream, Session), Error = Error> {
+    let username = account.node.clone().unwrap_or_default();
+    let direct_tls = port == Some(DIRECT_TLS_PORT);
+    let stream_account = account.clone();
+
+    tcp_connect(account.domain.clone(), server, port)
+        .and_then(move |tcp| match direct_tls {
+            true => Either::A(tls_connect(tcp, account)),
+            false => Either::B(starttls(tcp, account)),
+        })
+        .and_then(move |stream| authenticate(stream, username, password))
+        .and_then(|stream| stream.restart())
+        .and_then(move |stream| resume_or_bind(stream, stream_account, resume))
+}
+
+fn start_stream<S>(stream: S, account: &FullJid) -> impl Future<Item = XMPPStream<S>, Error = Error>
+    where S: tokio::io::AsyncRead + tokio::io::AsyncWrite
+{
+    // tokio_xmpp expects its own jid version, let inference pick it
+    let jid = FromStr::from_str(&account.to_string()).unwrap();
+    XMPPStream::start(stream, jid, ns::JABBER_CLIENT.to_owned())
+}
+
+fn starttls(tcp: TcpStream, account: FullJid) -> impl Future<Item = XmppStream, Error = Error> {
+    start_stream(tcp, &account)
+        .and_then(|stream| {
+            match stream.stream_features.get_child("starttls", NS_XMPP_TLS) {
+                Some(_) => Ok(StartTlsClient::from_stream(stream)),
+                None => Err(Error::Protocol(ProtocolError::NoTls)),
+            }
+        })
+        .flatten()
+        .and_then(move |tls| start_stream(tls, &account))
+}
+
+fn tls_connect(tcp: TcpStream, account: FullJid) -> impl Future<Item = XmppStream, Error = Error> {
+    let connector = match native_tls::TlsConnector::new() {
+        Ok(connector) => TlsConnector::from(connector),
+        Err(err) => return Either::A(future::err(Error::Tls(err))),
+    };
+
+    Either::B(connector.connect(&account.domain, tcp).map_err(Error::Tls).and_then(move |tls| start_stream(tls, &account)))
+}
+
+/// Try every host advertised by SRV records, falling back to the domain itself. A configured
+/// server or port bypasses the SRV lookup.
+fn tcp_connect(domain: String, server: Option<String>, port: Option<u16>) -> impl Future<Item = TcpStream, Error = Error> {
+    let (resolver, background) = match AsyncResolver::from_system_conf() {
+        Ok(resolver) => resolver,
+        Err(err) => return Either::A(future::err(Error::Connection(ConnecterError::Resolve(err)))),
+    };
+    tokio::runtime::current_thread::spawn(background);
+
+    let hosts = match (server, port) {
+        (None, None) => {
+            let srv = format!("_xmpp-client._tcp.{}.", domain);
+            Either::A(resolver.lookup_srv(srv.as_str()).then(move |result| {
+                let mut records = match result {
+                    Ok(records) => records.iter().map(|srv| (srv.priority(), srv.target().to_utf8(), srv.port())).collect(),
+                    Err(_) => Vec::new(),
+                };
+                records.sort_by_key(|(priority, _, _)| *priority);
+
+                let mut hosts = records.into_iter().map(|(_, host, port)| (host, port)).collect::<Vec<_>>();
+                if hosts.is_empty() {
+                    hosts.push((domain, DEFAULT_PORT));
+                }
+
+                Ok(hosts)
+            }))
+        },
+        (server, port) => Either::B(future::ok(vec![(server.unwrap_or(domain), port.unwrap_or(DEFAULT_PORT))])),
+    };
+
+    Either::B(hosts.and_then(|hosts: Vec<(String, u16)>| {
+        future::loop_fn((resolver, hosts.into_iter(), None), |(resolver, mut hosts, error)| {
+            match hosts.next() {
+                None => Either::A(future::err(error.unwrap_or(Error::Connection(ConnecterError::AllFailed)))),
+                Some((host, port)) => Either::B(tcp_connect_host(&resolver, &host, port).then(move |result| {
+                    match result {
+                        Ok(tcp) => Ok(Loop::Break(tcp)),
+                        Err(err) => Ok(Loop::Continue((resolver, hosts, Some(err)))),
+                    }
+                })),
+            }
+        })
+    }))
+}
+
+fn tcp_connect_host(resolver: &AsyncResolver, host: &str, port: u16) -> impl Future<Item = TcpStream, Error = Error> {
+    resolver.lookup_ip(host).map_err(|err| Error::Connection(ConnecterError::Resolve(err))).and_then(move |ips| {
+        let attempts = ips.iter().map(|ip| TcpStream::connect(&SocketAddr::new(ip, port))).collect::<Vec<_>>();
+        match attempts.is_empty() {
+            true => Either::A(future::err(Error::Connection(ConnecterError::AllFailed))),
+            false => Either::B(future::select_ok(attempts).map(|(tcp, _)| tcp).map_err(Error::Io)),
+        }
+    })
+}
+
+fn select_mechanism(stream: &XmppStream, credentials: Credentials) -> Result<Box<dyn Mechanism>, Error> {
+    let remote: Vec<String> = match stream.stream_features.get_child("mechanisms", ns::SASL) {
+        Some(mechanisms) => mechanisms.children().filter(|child| child.is("mechanism", ns::SASL)).map(|child| child.text()).collect(),
+        None => return Err(Error::Auth(AuthError::NoMechanism)),
+    };
+    let supports = |name: &str| remote.iter().any(|mechanism| mechanism == name);
+
+    let mechanism: Box<dyn Mechanism> = if supports("SCRAM-SHA-256") {
+        Box::new(Scram::<Sha256>::from_credentials(credentials).map_err(AuthError::Sasl)?)
+    } else if supports("SCRAM-SHA-1") {
+        Box::new(Scram::<Sha1>::from_credentials(credentials).map_err(AuthError::Sasl)?)
+    } else if supports("PLAIN") {
+        Box::new(Plain::from_credentials(credentials).map_err(AuthError::Sasl)?)
+    } else {
+        return Err(Error::Auth(AuthError::NoMechanism));
+    };
+
+    Ok(mechanism)
+}
+
+fn authenticate(stream: XmppStream, username: String, password: String) -> Box<dyn Future<Item = XmppStream, Error = Error>> {
+    // Like tokio_xmpp, tell the server we don't support channel binding rather than that it doesn't
+    let credentials = Credentials::default()
+        .with_username(username)
+        .with_password(password)
+        .with_channel_binding(ChannelBinding::None);
+
+    let mut mechanism = match select_mechanism(&stream, credentials) {
+        Ok(mechanism) => mechanism,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    let auth = match (SaslMechanism::from_str(mechanism.name()), mechanism.initial()) {
+        (Ok(name), Ok(data)) => Auth { mechanism: name, data: data },
+        (_, Err(err)) => return Box::new(future::err(Error::Auth(AuthError::Sasl(err)))),
+        (Err(_), _) => return Box::new(future::err(Error::Auth(AuthError::NoMechanism))),
+    };
+
+    Box::new(stream.send_stanza(auth).map_err(Error::Io).and_then(|stream| {
+        future::loop_fn((stream, mechanism), |(stream, mut mechanism)| {
+            next_element(stream).and_then(move |(element, stream)| -> Box<dyn Future<Item = Loop<XmppStream, (XmppStream, Box<dyn Mechanism>)>, Error = Error>> {
+                if let Ok(challenge) = Challenge::try_from(element.clone()) {
+                    match mechanism.response(&challenge.data) {
+                        Ok(data) => Box::new(stream.send_stanza(Response { data: data }).map_err(Error::Io).map(|stream| Loop::Continue((stream, mechanism)))),
+                        Err(err) => Box::new(future::err(Error::Auth(AuthError::Sasl(err)))),
+                    }
+                } else if let Ok(success) = Success::try_from(element.clone()) {
+                    match mechanism.success(&success.data) {
+                        Ok(()) => Box::new(future::ok(Loop::Break(stream))),
+                        Err(err) => Box::new(future::err(Error::Auth(AuthError::Sasl(err)))),
+                    }
+                } else if Failure::try_from(element.clone()).is_ok() || element.is("failure", ns::SASL) {
+                    // AuthError wraps tokio_xmpp's own parsers version, let inference pick it
+                    let failure = element.children().next().and_then(|condition| TryFrom::try_from(condition.clone()).ok());
+                    let error = match failure {
+                        Some(condition) => AuthError::Fail(condition),
+                        None => AuthError::Sasl(String::from("failure")),
+                    };
+                    Box::new(future::err(Error::Auth(error)))
+                } else {
+                    Box::new(future::ok(Loop::Continue((stream, mechanism))))
+                }
+            })
+        })
+    }))
+}
+
+fn resume_or_bind(stream: XmppStream, account: FullJid, resume: Option<(String, u32)>) -> Box<dyn Future<Item = (XmppStream, Session), Error = Error>> {
+    let stream_management = stream.stream_features.get_child("sm", ns::SM).is_some();
+
+    match resume {
+        Some((previd, h)) if stream_management => {
+            let resume = sm::Resume { h: h, previd: sm::StreamId(previd) };
+            Box::new(stream.send_stanza(resume).map_err(Error::Io).and_then(next_element).and_then(move |(element, stream)| {
+                match sm::Resumed::try_from(element) {
+                    Ok(resumed) => Either::A(future::ok((stream, Session::Resumed(resumed.h)))),
+                    Err(_) => Either::B(bind(stream, &account).map(|(stream, jid)| (stream, Session::New { jid: jid, stream_management: true }))),
+                }
+            }))
+        },
+        _ => Box::new(bind(stream, &account).map(move |(stream, jid)| (stream, Session::New { jid: jid, stream_management: stream_management }))),
+    }
+}
+
+/// Bind the requested resource, resolves with the JID the server actually bound
+fn bind(stream: XmppStream, account: &FullJid) -> impl Future<Item = (XmppStream, FullJid), Error = Error> {
+    let iq = Iq::from_set(BIND_ID, BindQuery::new(Some(account.resource.clone())));
+
+    stream.send_stanza(iq).map_err(Error::Io).and_then(|stream| {
+        future::loop_fn(stream, |stream| {
+            next_element(stream).and_then(|(element, stream)| {
+                match bind_result(element)? {
+                    Some(jid) => Ok(Loop::Break((stream, jid))),
+                    None => Ok(Loop::Continue(stream)),
+                }
+            })
+        })
+    })
+}
+
+/// JID bound by the server if the element answers our bind request, None if it's another element
+fn bind_result(element: Element) -> Result<Option<FullJid>, Error> {
+    match Iq::try_from(element) {
+        Ok(Iq { id, payload: IqType::Result(Some(payload)), .. }) if id == BIND_ID => match BindResponse::try_from(payload) {
+            Ok(bound) => Ok(Some(FullJid::from(bound))),
+            Err(_) => Err(Error::Protocol(ProtocolError::InvalidBindResponse)),
+        },
+        Ok(Iq { ref id, .. }) if id == BIND_ID => Err(Error::Protocol(ProtocolError::InvalidBindResponse)),
+        _ => Ok(None),
+    }
+}
+
+/// Read packets until the next stanza or nonza
+fn next_element(stream: XmppStream) -> impl Future<Item = (Element, XmppStream), Error = Error> {
+    future::loop_fn(stream, |stream| {
+        stream.into_future().map_err(|(err, _)| Error::from(err)).and_then(|(packet, stream)| {
+            match packet {
+                Some(Packet::Stanza(element)) => Ok(Loop::Break((element, stream))),
+                Some(Packet::Text(_)) => Ok(Loop::Continue(stream)),
+                _ => Err(Error::Disconnected),
+            }
+        })
+    })
+}
+
+/// XEP-0198 state of an account. It outlives the connection so that stanzas the server didn't
+/// acknowledge can be resent once the session is resumed or a new one is started.
+pub struct StreamManagement {
+    id: Option<String>,
+    inbound: u32,
+    acked: u32,
+    unacked: VecDeque<Element>,
+}
+
+impl StreamManagement {
+    pub fn new() -> Self {
+        Self {
+            id: None,
+            inbound: 0,
+            acked: 0,
+            unacked: VecDeque::new(),
+        }
+    }
+
+    pub fn enabled(&mut self, enabled: sm::Enabled) {
+        if enabled.resume == sm::ResumeAttr::True {
+            self.id = enabled.id.map(|id| id.0);
+        }
+    }
+
+    /// Session id and inbound counter to resume with, if the server allowed resumption
+    pub fn resumption(&self) -> Option<(String, u32)> {
+        self.id.clone().map(|id| (id, self.inbound))
+    }
+
+    pub fn received(&mut self) {
+        self.inbound = self.inbound.wrapping_add(1);
+    }
+
+    pub fn inbound(&self) -> u32 {
+        self.inbound
+    }
+
+    pub fn sent(&mut self, stanza: Element) {
+        self.unacked.push_back(stanza);
+    }
+
+    /// Handle the server's count of handled stanzas, returns the newly acknowledged ones
+    pub fn ack(&mut self, h: u32) -> Vec<Element> {
+        let count = h.wrapping_sub(self.acked) as usize;
+        let count = std::cmp::min(count, self.unacked.len());
+        self.acked = h;
+        self.unacked.drain(..count).collect()
+    }
+
+    pub fn take_unacked(&mut self) -> Vec<Element> {
+        self.unacked.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stanza(id: &str) -> Element {
+        Element::builder("message").ns(ns::JABBER_CLIENT).attr("id", id).build()
+    }
+
+    #[test]
+    fn test_ack_pops_handled_stanzas() {
+        let mut state = StreamManagement::new();
+        state.sent(stanza("1"));
+        state.sent(stanza("2"));
+        state.sent(stanza("3"));
+
+        let acked = state.ack(2);
+        assert_eq!(acked.len(), 2);
+        assert_eq!(acked[1].attr("id"), Some("2"));
+
+        let acked = state.ack(3);
+        assert_eq!(acked.len(), 1);
+        assert_eq!(acked[0].attr("id"), Some("3"));
+        assert!(state.take_unacked().is_empty());
+    }
+
+    #[test]
+    fn test_ack_after_resend() {
+        let mut state = StreamManagement::new();
+        state.sent(stanza("1"));
+        state.sent(stanza("2"));
+
+        // Server handled only the first one before the stream broke
+        assert_eq!(state.ack(1).len(), 1);
+        for stanza in state.take_unacked() {
+            state.sent(stanza);
+        }
+
+        let acked = state.ack(2);
+        assert_eq!(acked.len(), 1);
+        assert_eq!(acked[0].attr("id"), Some("2"));
+    }
+
+    #[test]
+    fn test_bind_result_is_the_bound_jid() {
+        let result = |xml: &str| bind_result(Element::from_str(xml).unwrap());
+
+        let bound = result("<iq xmlns='jabber:client' type='result' id='resource-bind'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><jid>me@server.tld/aparte.1234</jid></bind></iq>").unwrap();
+        assert_eq!(bound, Some(FullJid::from_str("me@server.tld/aparte.1234").unwrap()));
+
+        assert!(result("<iq xmlns='jabber:client' type='result' id='resource-bind'/>").is_err());
+        assert!(result("<iq xmlns='jabber:client' type='error' id='resource-bind'><error type='cancel'><conflict xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></iq>").is_err());
+        assert_eq!(result("<message xmlns='jabber:client'/>").unwrap(), None);
+    }
+
+    #[test]
+    fn test_resumption_requires_resumable_session() {
+        let mut state = StreamManagement::new();
+        state.received();
+        assert_eq!(state.resumption(), None);
+
+        state.enabled(sm::Enabled { id: Some(sm::StreamId(String::from("id"))), location: None, max: None, resume: sm::ResumeAttr::True });
+        assert_eq!(state.resumption(), Some((String::from("id"), 1)));
+    }
+}
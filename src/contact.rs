@@ -1,6 +1,7 @@
 use xmpp_parsers::roster::Subscription;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
-use xmpp_parsers::BareJid;
+use xmpp_parsers::{BareJid, FullJid};
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub enum Presence {
@@ -12,6 +13,28 @@ pub enum Presence {
     Xa,
 }
 
+impl Presence {
+    /// Rank used to pick the most available resource among those of equal priority
+    fn availability(&self) -> u8 {
+        match self {
+            Presence::Chat => 5,
+            Presence::Available => 4,
+            Presence::Away => 3,
+            Presence::Xa => 2,
+            Presence::Dnd => 1,
+            Presence::Unavailable => 0,
+        }
+    }
+}
+
+/// Presence of one connected resource of a contact
+#[derive(Clone, Debug)]
+pub struct Resource {
+    pub presence: Presence,
+    pub status: Option<String>,
+    pub priority: i8,
+}
+
 #[derive(Clone, Debug)]
 pub struct Group(pub String);
 
@@ -31,23 +54,128 @@ impl Eq for Group {}
 
 #[derive(Clone, Debug)]
 pub struct Contact {
+    /// Account whose roster holds the contact
+    pub account: BareJid,
     pub jid: BareJid,
     pub name: Option<String>,
     pub subscription: Subscription,
+    /// Aggregate presence, the one of the highest priority resource
     pub presence: Presence,
+    /// Status message of the highest priority resource
+    pub status: Option<String>,
+    pub resources: HashMap<String, Resource>,
     pub groups: Vec<Group>,
 }
 
+impl Contact {
+    /// Update the presence of a resource, None when it went offline
+    pub fn set_resource(&mut self, resource: String, presence: Option<Resource>) {
+        match presence {
+            Some(presence) => self.resources.insert(resource, presence),
+            None => self.resources.remove(&resource),
+        };
+
+        let best = self.resources.values().max_by_key(|resource| (resource.priority, resource.presence.availability()));
+        match best {
+            Some(best) => {
+                self.presence = best.presence.clone();
+                self.status = best.status.clone();
+            },
+            None => {
+                self.presence = Presence::Unavailable;
+                self.status = None;
+            },
+        }
+    }
+}
+
 impl Hash for Contact {
     fn hash<H: Hasher>(&self, state: &mut H) {
+        self.account.hash(state);
         self.jid.hash(state);
     }
 }
 
 impl PartialEq for Contact {
     fn eq(&self, other: &Self) -> bool {
-        self.jid == other.jid
+        self.account == other.account && self.jid == other.jid
     }
 }
 
 impl Eq for Contact {}
+
+/// Pending request of someone wanting to subscribe to our presence
+#[derive(Clone, Debug)]
+pub struct SubscriptionRequest {
+    pub account: FullJid,
+    pub jid: BareJid,
+    pub status: Option<String>,
+}
+
+impl Hash for SubscriptionRequest {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.account.hash(state);
+        self.jid.hash(state);
+    }
+}
+
+impl PartialEq for SubscriptionRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.account == other.account && self.jid == other.jid
+    }
+}
+
+impl Eq for SubscriptionRequest {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn contact() -> Contact {
+        Contact {
+            account: BareJid::from_str("me@server.tld").unwrap(),
+            jid: BareJid::from_str("contact@server.tld").unwrap(),
+            name: None,
+            subscription: Subscription::Both,
+            presence: Presence::Unavailable,
+            status: None,
+            resources: HashMap::new(),
+            groups: Vec::new(),
+        }
+    }
+
+    fn resource(presence: Presence, status: &str, priority: i8) -> Option<Resource> {
+        Some(Resource { presence: presence, status: Some(status.to_string()), priority: priority })
+    }
+
+    #[test]
+    fn test_aggregate_presence_follows_priority() {
+        let mut contact = contact();
+        contact.set_resource("phone".to_string(), resource(Presence::Away, "On the go", 0));
+        contact.set_resource("desktop".to_string(), resource(Presence::Dnd, "Working", 10));
+
+        assert_eq!(contact.presence, Presence::Dnd);
+        assert_eq!(contact.status, Some("Working".to_string()));
+
+        contact.set_resource("desktop".to_string(), None);
+
+        assert_eq!(contact.presence, Presence::Away);
+        assert_eq!(contact.status, Some("On the go".to_string()));
+    }
+
+    #[test]
+    fn test_unavailable_resource_does_not_hide_others() {
+        let mut contact = contact();
+        contact.set_resource("phone".to_string(), resource(Presence::Available, "Hi", 0));
+        contact.set_resource("laptop".to_string(), resource(Presence::Xa, "Gone", 0));
+
+        assert_eq!(contact.presence, Presence::Available);
+
+        contact.set_resource("phone".to_string(), None);
+        contact.set_resource("laptop".to_string(), None);
+
+        assert_eq!(contact.presence, Presence::Unavailable);
+        assert_eq!(contact.status, None);
+    }
+}
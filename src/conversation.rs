@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
-use xmpp_parsers::BareJid;
+use xmpp_parsers::{BareJid, FullJid};
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug, Copy)]
 pub enum Affiliation {
@@ -28,13 +28,19 @@ pub struct Occupant {
 
 #[derive(Clone, Debug)]
 pub struct Channel {
+    pub account: FullJid,
     pub jid: BareJid,
     pub nick: String,
     pub name: Option<String>,
     pub occupants: HashMap<String, Occupant>,
+    pub subject: Option<String>,
+    pub password: Option<String>,
+    /// Whether our own presence was reflected, occupants received before are already there
+    pub joined: bool,
 }
 
 pub struct Chat {
+    pub account: FullJid,
     pub contact: BareJid,
 }
 
@@ -1,42 +1,203 @@
-use futures::Sink;
+use chrono::{DateTime, Utc};
+use futures::{Future, Sink};
 use futures::unsync::mpsc::UnboundedSender;
+use futures::unsync::oneshot;
 use std::any::{Any, TypeId};
-use std::cell::{RefCell, RefMut, Ref};
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell, RefMut, Ref};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::timer::Timeout;
 use tokio_xmpp::Packet;
-use xmpp_parsers::{Element, FullJid, BareJid, presence, iq};
+use xmpp_parsers::{Element, FullJid, BareJid, Jid, ns, presence, iq, sm};
+use xmpp_parsers::data_forms::DataForm;
+use xmpp_parsers::disco::{DiscoInfoResult, DiscoItemsResult};
+use xmpp_parsers::mam;
+use xmpp_parsers::pubsub::PubSubEvent;
+use xmpp_parsers::stanza_error::StanzaError;
 use xmpp_parsers;
 
 use crate::{contact, conversation};
 use crate::message::Message;
 use crate::command::{Command, CommandParser};
+use crate::account::Account;
 use crate::config::Config;
+use crate::plugins::disco;
+use crate::connection::StreamManagement;
 
 #[derive(Debug, Clone)]
 pub enum CommandOrMessage {
     Command(Command),
-    Message(Message),
+    Message(FullJid, Message),
+}
+
+const IQ_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum IqError {
+    Stanza(StanzaError),
+    Timeout,
+    Disconnected,
+    Invalid,
+}
+
+impl fmt::Display for IqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IqError::Stanza(error) => write!(f, "{}", stanza_error_text(error)),
+            IqError::Timeout => write!(f, "timeout"),
+            IqError::Disconnected => write!(f, "disconnected"),
+            IqError::Invalid => write!(f, "invalid response"),
+        }
+    }
+}
+
+struct PendingIq {
+    account: FullJid,
+    to: Option<Jid>,
+    sender: oneshot::Sender<Result<Option<Element>, IqError>>,
+}
+
+/// Condition of an error stanza, followed by the text the entity gave if any
+pub fn stanza_error_text(error: &StanzaError) -> String {
+    let condition: Element = error.defined_condition.clone().into();
+    match error.texts.values().next() {
+        Some(text) => format!("{} ({})", condition.name(), text),
+        None => condition.name().to_string(),
+    }
+}
+
+/// Serialize a stanza for the debug log, with passwords masked
+pub fn redacted(element: &Element) -> String {
+    fn redact(element: &mut Element) {
+        if element.name() == "password" || element.is("auth", ns::SASL) || element.is("response", ns::SASL) {
+            for text in element.texts_mut() {
+                *text = String::from("******");
+            }
+        }
+
+        for child in element.children_mut() {
+            redact(child);
+        }
+    }
+
+    let mut element = element.clone();
+    redact(&mut element);
+    String::from(&element)
 }
 
 pub enum Event {
     Connected(FullJid),
-    #[allow(dead_code)]
     Disconnected(FullJid),
-    Message(Message),
-    Chat(BareJid),
-    Join(FullJid),
-    Iq(iq::Iq),
-    Presence(presence::Presence),
+    Resumed(FullJid),
+    Message(Option<FullJid>, Message),
+    MessageAcked(FullJid, String),
+    /// An outgoing message the server may not have handled, stream management couldn't tell
+    MessageFailed(FullJid, String),
+    Chat {
+        account: FullJid,
+        contact: BareJid,
+    },
+    Join {
+        account: FullJid,
+        channel: FullJid,
+        password: Option<String>,
+    },
+    Iq(FullJid, iq::Iq),
+    Presence(FullJid, presence::Presence),
     ReadPassword(Command),
     Win(String),
-    Contact(contact::Contact),
-    ContactUpdate(contact::Contact),
-    Occupant(conversation::Occupant),
+    /// Window closed, along with the conversation it shows
+    Close(String),
+    /// Conversation forgotten once its window is closed
+    ConversationClosed {
+        account: FullJid,
+        conversation: BareJid,
+    },
+    Contact(FullJid, contact::Contact),
+    ContactUpdate(FullJid, contact::Contact),
+    ContactRemoved(FullJid, contact::Contact),
+    SubscriptionRequest(FullJid, contact::SubscriptionRequest),
+    SubscriptionRequestRemoved(FullJid, contact::SubscriptionRequest),
+    Disco {
+        account: FullJid,
+        jid: Jid,
+        info: DiscoInfoResult,
+        items: Option<DiscoItemsResult>,
+    },
+    Occupant {
+        account: FullJid,
+        conversation: BareJid,
+        occupant: conversation::Occupant,
+    },
+    OccupantRemoved {
+        account: FullJid,
+        conversation: BareJid,
+        occupant: conversation::Occupant,
+    },
+    Subject {
+        account: FullJid,
+        channel: BareJid,
+        subject: Option<String>,
+        by: Option<String>,
+    },
+    RoomConfig {
+        account: FullJid,
+        channel: BareJid,
+        form: DataForm,
+    },
+    /// Older messages of a conversation are needed, from the local history first
+    LoadHistory {
+        account: FullJid,
+        conversation: BareJid,
+        groupchat: bool,
+    },
+    /// Older messages of a conversation are needed and none are stored locally, from its archive
+    LoadArchive {
+        account: FullJid,
+        conversation: BareJid,
+        groupchat: bool,
+        /// Time of the oldest message shown, the archive is fetched from there
+        end: Option<DateTime<Utc>>,
+    },
+    /// Messages of a conversation received while its window was closed, from its archive
+    CatchUpArchive {
+        account: FullJid,
+        conversation: BareJid,
+        groupchat: bool,
+        /// Time of the newest message stored, the archive is fetched from there
+        start: DateTime<Utc>,
+    },
+    /// Page of messages fetched from the archive of a conversation, oldest first
+    ArchivedMessages {
+        account: FullJid,
+        conversation: BareJid,
+        messages: Vec<Message>,
+    },
+    /// Message from an archive, in response to one of our queries
+    Archive {
+        account: FullJid,
+        from: Option<Jid>,
+        result: mam::Result_,
+    },
+    /// PEP notification received from one of our contacts or ourselves
+    PubSub {
+        account: FullJid,
+        from: Jid,
+        event: PubSubEvent,
+    },
+    /// Informational line to print in a conversation window
+    Notice {
+        account: FullJid,
+        conversation: BareJid,
+        text: String,
+    },
     Signal(i32),
     Quit,
 }
@@ -70,6 +231,7 @@ impl<T> AnyPlugin for T where T: Any + Plugin {
 pub struct Connection {
     pub sink: UnboundedSender<Packet>,
     pub account: FullJid,
+    pub features: Element,
 }
 
 pub struct Aparte {
@@ -77,14 +239,21 @@ pub struct Aparte {
     plugins: HashMap<TypeId, RefCell<Box<dyn AnyPlugin>>>,
     connections: RefCell<HashMap<String, Connection>>,
     current_connection: RefCell<Option<String>>,
+    pending_iqs: RefCell<HashMap<String, PendingIq>>,
+    /// Stream management state of each account, whatever resource its sessions are bound to
+    stream_management: RefCell<HashMap<BareJid, StreamManagement>>,
+    /// Generation of the latest connection attempt of each account, older attempts stop when they
+    /// see they were replaced or cancelled
+    connection_generations: RefCell<HashMap<BareJid, u64>>,
+    next_generation: Cell<u64>,
     event_lock: RefCell<()>,
     event_queue: RefCell<Vec<Event>>,
     pub config: Config,
-
+    pub data_dir: PathBuf,
 }
 
 impl Aparte {
-    pub fn new(config_path: PathBuf) -> Self {
+    pub fn new(config_path: PathBuf, data_dir: PathBuf) -> Self {
         let mut config_file = match OpenOptions::new().read(true).write(true).create(true).open(config_path) {
             Err(err) => panic!("Cannot read config file {}", err),
             Ok(config_file) => config_file,
@@ -102,9 +271,14 @@ impl Aparte {
             plugins: HashMap::new(),
             connections: RefCell::new(HashMap::new()),
             current_connection: RefCell::new(None),
+            pending_iqs: RefCell::new(HashMap::new()),
+            stream_management: RefCell::new(HashMap::new()),
+            connection_generations: RefCell::new(HashMap::new()),
+            next_generation: Cell::new(0),
             event_lock: RefCell::new(()),
             event_queue: RefCell::new(Vec::new()),
             config: config,
+            data_dir: data_dir,
         }
     }
 
@@ -166,10 +340,11 @@ impl Aparte {
         Some(RefMut::map(any_plugin, |p| p.as_any_mut().downcast_mut::<T>().unwrap()))
     }
 
-    pub fn add_connection(&self, account: FullJid, sink: UnboundedSender<Packet>) {
+    pub fn add_connection(&self, account: FullJid, sink: UnboundedSender<Packet>, features: Element) {
         let connection = Connection {
             account: account,
             sink: sink,
+            features: features,
         };
 
         let account = connection.account.to_string();
@@ -178,6 +353,70 @@ impl Aparte {
         self.current_connection.replace(Some(account.clone()));
     }
 
+    pub fn remove_connection(&self, account: &FullJid) {
+        let name = account.to_string();
+
+        self.connections.borrow_mut().remove(&name);
+        if self.current_connection.borrow().as_ref() == Some(&name) {
+            let next = self.connections.borrow().keys().next().cloned();
+            self.current_connection.replace(next);
+        }
+
+        // Answers to pending requests still come once the session is resumed, they are failed when
+        // a new session is started instead
+        if self.resumption(account).is_none() {
+            self.fail_pending_iqs(account, &HashSet::new());
+        }
+    }
+
+    /// Resolve the requests of an account to IqError::Disconnected, except those with the given
+    /// ids. Dropping the senders is enough.
+    fn fail_pending_iqs(&self, account: &FullJid, keep: &HashSet<String>) {
+        let account: BareJid = account.clone().into();
+        self.pending_iqs.borrow_mut().retain(|id, pending| BareJid::from(Jid::Full(pending.account.clone())) != account || keep.contains(id));
+    }
+
+    /// Start a new connection attempt for an account, replacing any previous one along with the
+    /// reconnections it scheduled
+    pub fn new_connection_generation(&self, account: &FullJid) -> u64 {
+        let generation = self.next_generation.get();
+        self.next_generation.set(generation + 1);
+        self.connection_generations.borrow_mut().insert(account.clone().into(), generation);
+        generation
+    }
+
+    /// Whether a connection attempt is still the one wanted for its account
+    pub fn is_current_generation(&self, account: &FullJid, generation: u64) -> bool {
+        let account: BareJid = account.clone().into();
+        self.connection_generations.borrow().get(&account) == Some(&generation)
+    }
+
+    /// Stop every connection attempt, no reconnection is made afterwards
+    pub fn cancel_connections(&self) {
+        self.connection_generations.borrow_mut().clear();
+    }
+
+    /// Stream features the server of this account advertised
+    pub fn stream_features(&self, account: &FullJid) -> Option<Element> {
+        self.connections.borrow().get(&account.to_string()).map(|connection| connection.features.clone())
+    }
+
+    /// Accounts currently connected
+    pub fn accounts(&self) -> Vec<FullJid> {
+        self.connections.borrow().values().map(|connection| connection.account.clone()).collect()
+    }
+
+    /// Configuration of a connected account, if it comes from the config file
+    pub fn account_config(&self, account: &FullJid) -> Option<&Account> {
+        let bare: BareJid = account.clone().into();
+        self.config.accounts.values().find(|config| {
+            match Jid::from_str(&config.login) {
+                Ok(login) => BareJid::from(login) == bare,
+                Err(_) => false,
+            }
+        })
+    }
+
     pub fn current_connection(&self) -> Option<FullJid> {
         let current_connection = self.current_connection.borrow();
         match &*current_connection {
@@ -200,18 +439,208 @@ impl Aparte {
         Ok(())
     }
 
-    pub fn send(&self, element: Element) {
-        debug!("SEND: {:?}", element);
+    pub fn send(&self, account: &FullJid, element: Element) {
+        let element = self.with_caps(element);
+        debug!("SEND: {}", redacted(&element));
+
+        let queued = match self.stream_management.borrow_mut().get_mut(&BareJid::from(Jid::Full(account.clone()))) {
+            Some(stream_management) => {
+                stream_management.sent(element.clone());
+                true
+            },
+            None => false,
+        };
+
+        let request_ack = queued && element.is("message", ns::DEFAULT_NS);
+        if !self.send_packet(account, element) && !queued {
+            warn!("Cannot send packet: no connection for {}", account);
+        }
+
+        if request_ack {
+            self.send_packet(account, sm::R.into());
+        }
+    }
+
+    /// Attach our entity capabilities to outgoing available presences
+    fn with_caps(&self, mut element: Element) -> Element {
+        if needs_caps(&element) {
+            if let Some(disco) = self.get_plugin::<disco::Disco>() {
+                element.append_child(disco.caps().clone().into());
+            }
+        }
+
+        element
+    }
+
+    fn send_packet(&self, account: &FullJid, element: Element) -> bool {
         let packet = Packet::Stanza(element);
-        // TODO use correct connection
         let mut connections = self.connections.borrow_mut();
-        let current_connection = connections.iter_mut().next().unwrap().1;
-        let mut sink = &current_connection.sink;
-        if let Err(e) = sink.start_send(packet) {
-            warn!("Cannot send packet: {}", e);
+        match connections.get_mut(&account.to_string()) {
+            Some(connection) => {
+                let mut sink = &connection.sink;
+                if let Err(e) = sink.start_send(packet) {
+                    warn!("Cannot send packet: {}", e);
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Stream management session the next connection of this account should try to resume
+    pub fn resumption(&self, account: &FullJid) -> Option<(String, u32)> {
+        match self.stream_management.borrow().get(&BareJid::from(Jid::Full(account.clone()))) {
+            Some(stream_management) => stream_management.resumption(),
+            None => None,
+        }
+    }
+
+    /// Start over stream management on a newly bound session, and resend what the previous
+    /// session didn't get acknowledged.
+    pub fn new_session(&self, account: &FullJid, stream_management: bool) {
+        let unacked = match self.stream_management.borrow_mut().remove(&BareJid::from(Jid::Full(account.clone()))) {
+            Some(mut previous) => previous.take_unacked(),
+            None => Vec::new(),
+        };
+
+        // Requests the previous session handled won't be answered, those resent will be
+        let resent = unacked.iter().filter(|stanza| stanza.is("iq", ns::DEFAULT_NS)).filter_map(|stanza| stanza.attr("id")).map(String::from).collect();
+        self.fail_pending_iqs(account, &resent);
+
+        if stream_management {
+            self.send_packet(account, sm::Enable::new().with_resume().into());
+            self.stream_management.borrow_mut().insert(account.clone().into(), StreamManagement::new());
+        }
+
+        for stanza in unacked {
+            self.send(account, stanza);
+        }
+    }
+
+    /// Resend what the server didn't handle before the stream broke
+    pub fn resume_session(self: Rc<Self>, account: &FullJid, h: u32) {
+        Rc::clone(&self).acked(account, h);
+
+        let unacked = match self.stream_management.borrow_mut().get_mut(&BareJid::from(Jid::Full(account.clone()))) {
+            Some(stream_management) => stream_management.take_unacked(),
+            None => Vec::new(),
+        };
+
+        for stanza in unacked {
+            self.send(account, stanza);
+        }
+    }
+
+    fn acked(self: Rc<Self>, account: &FullJid, h: u32) {
+        let acked = match self.stream_management.borrow_mut().get_mut(&BareJid::from(Jid::Full(account.clone()))) {
+            Some(stream_management) => stream_management.ack(h),
+            None => Vec::new(),
+        };
+
+        for stanza in acked {
+            if let (true, Some(id)) = (stanza.is("message", ns::DEFAULT_NS), stanza.attr("id")) {
+                Rc::clone(&self).event(Event::MessageAcked(account.clone(), id.to_string()));
+            }
+        }
+    }
+
+    /// Count a received stanza for stream management acknowledgements
+    pub fn stanza_received(&self, account: &FullJid) {
+        if let Some(stream_management) = self.stream_management.borrow_mut().get_mut(&BareJid::from(Jid::Full(account.clone()))) {
+            stream_management.received();
         }
     }
 
+    pub fn handle_stream_management(self: Rc<Self>, account: &FullJid, element: Element) {
+        if element.is("r", ns::SM) {
+            let h = match self.stream_management.borrow().get(&BareJid::from(Jid::Full(account.clone()))) {
+                Some(stream_management) => stream_management.inbound(),
+                None => return,
+            };
+            self.send_packet(account, sm::A::new(h).into());
+        } else if let Ok(a) = sm::A::try_from(element.clone()) {
+            self.acked(account, a.h);
+        } else if let Ok(enabled) = sm::Enabled::try_from(element.clone()) {
+            if let Some(stream_management) = self.stream_management.borrow_mut().get_mut(&BareJid::from(Jid::Full(account.clone()))) {
+                stream_management.enabled(enabled);
+            }
+        } else if sm::Failed::try_from(element).is_ok() {
+            warn!("Stream management failed for {}", account);
+            // Nothing will tell anymore whether the server handled what it didn't acknowledge yet
+            let unacked = match self.stream_management.borrow_mut().remove(&BareJid::from(Jid::Full(account.clone()))) {
+                Some(mut stream_management) => stream_management.take_unacked(),
+                None => Vec::new(),
+            };
+
+            for stanza in unacked {
+                if let (true, Some(id)) = (stanza.is("message", ns::DEFAULT_NS), stanza.attr("id")) {
+                    Rc::clone(&self).event(Event::MessageFailed(account.clone(), id.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Send an IQ request and resolve with the payload of its result, or with the error the
+    /// requested entity answered. Resolves to `IqError::Timeout` if no answer comes in time.
+    pub fn iq(self: Rc<Self>, account: &FullJid, iq: iq::Iq) -> impl Future<Item = Option<Element>, Error = IqError> {
+        let (sender, receiver) = oneshot::channel();
+        let id = iq.id.clone();
+
+        self.pending_iqs.borrow_mut().insert(id.clone(), PendingIq {
+            account: account.clone(),
+            to: iq.to.clone(),
+            sender: sender,
+        });
+        self.send(account, iq.into());
+
+        Timeout::new(receiver, IQ_TIMEOUT).then(move |result| {
+            self.pending_iqs.borrow_mut().remove(&id);
+            match result {
+                Ok(result) => result,
+                Err(err) => match err.is_elapsed() {
+                    true => Err(IqError::Timeout),
+                    false => Err(IqError::Disconnected),
+                },
+            }
+        })
+    }
+
+    /// Resolve the pending request matching a received IQ response. Returns false when the IQ
+    /// isn't an answer to one of our requests.
+    pub fn handle_iq_response(&self, account: &FullJid, iq: &iq::Iq) -> bool {
+        let result = match &iq.payload {
+            iq::IqType::Result(payload) => Ok(payload.clone()),
+            iq::IqType::Error(error) => Err(IqError::Stanza(error.clone())),
+            iq::IqType::Get(_) | iq::IqType::Set(_) => return false,
+        };
+
+        let mut pending_iqs = self.pending_iqs.borrow_mut();
+        let expected = match pending_iqs.get(&iq.id) {
+            Some(pending) => {
+                let account_bare: BareJid = account.clone().into();
+                let from_us = match &iq.from {
+                    None => true,
+                    Some(Jid::Bare(from)) => from == &account_bare || from.node.is_none() && from.domain == account.domain,
+                    Some(Jid::Full(from)) => from == account,
+                };
+
+                &pending.account == account && match &pending.to {
+                    Some(to) => iq.from.as_ref() == Some(to) || (iq.from.is_none() && to == &Jid::Bare(account_bare)),
+                    None => from_us,
+                }
+            },
+            None => false,
+        };
+
+        if !expected {
+            return false;
+        }
+
+        let pending = pending_iqs.remove(&iq.id).unwrap();
+        let _ = pending.sender.send(result);
+        true
+    }
+
     pub fn event(self: Rc<Self>, event: Event) {
         self.event_queue.borrow_mut().push(event);
         if let Ok(_lock) = self.event_lock.try_borrow_mut() {
@@ -226,7 +655,7 @@ impl Aparte {
 
     pub fn log(self: Rc<Self>, message: String) {
         let message = Message::log(message);
-        self.event(Event::Message(message));
+        self.event(Event::Message(None, message));
     }
 }
 
@@ -267,7 +696,7 @@ macro_rules! parse_command_args {
 
         $index += 1;
 
-        parse_command_args!($command, $index, $($(($attr))? $args),*);
+        parse_command_args!($aparte, $command, $index, $($(($attr))? $args),*);
     );
     ($aparte:ident, $command:ident, $index:ident, $arg:ident, $($(($attr:ident))? $args:ident),+) => (
         if $command.args.len() <= $index {
@@ -339,6 +768,12 @@ macro_rules! command_def {
     );
 }
 
+/// Whether a stanza is an available presence without capabilities, unavailable and subscription
+/// presences must not advertise them
+fn needs_caps(element: &Element) -> bool {
+    element.is("presence", ns::DEFAULT_NS) && element.attr("type").is_none() && !element.has_child("c", ns::CAPS)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -351,6 +786,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_redacted_masks_passwords() {
+        let muc = Element::builder("x").ns("http://jabber.org/protocol/muc")
+            .append(Element::builder("password").ns("http://jabber.org/protocol/muc").append("secret").build())
+            .build();
+        let presence = Element::builder("presence").ns(ns::DEFAULT_NS).append(muc).build();
+
+        let log = redacted(&presence);
+        assert!(!log.contains("secret"));
+        assert!(log.contains("******"));
+    }
+
+    #[test]
+    fn test_caps_only_in_available_presences() {
+        assert!(needs_caps(&Element::builder("presence").ns(ns::DEFAULT_NS).build()));
+        assert!(!needs_caps(&Element::builder("presence").ns(ns::DEFAULT_NS).attr("type", "unavailable").build()));
+        assert!(!needs_caps(&Element::builder("presence").ns(ns::DEFAULT_NS).attr("type", "subscribed").build()));
+        assert!(!needs_caps(&Element::builder("message").ns(ns::DEFAULT_NS).build()));
+    }
+
     #[test]
     fn test_command_without_args() {
         let cmd = no_args();
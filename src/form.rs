@@ -0,0 +1,195 @@
+use std::str::FromStr;
+use xmpp_parsers::Jid;
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+
+/// Whether the user can change the value of a field
+pub fn editable(field: &Field) -> bool {
+    match field.type_ {
+        FieldType::Fixed | FieldType::Hidden => false,
+        _ => true,
+    }
+}
+
+fn is_true(field: &Field) -> bool {
+    match field.values.first().map(String::as_str) {
+        Some("1") | Some("true") => true,
+        _ => false,
+    }
+}
+
+fn option_label(field: &Field, value: &str) -> String {
+    match field.options.iter().find(|option| option.value == value) {
+        Some(option) => option.label.clone().unwrap_or_else(|| option.value.clone()),
+        None => value.to_string(),
+    }
+}
+
+/// Human readable value of a field
+pub fn display_value(field: &Field) -> String {
+    match field.type_ {
+        FieldType::Boolean => match is_true(field) {
+            true => String::from("[x]"),
+            false => String::from("[ ]"),
+        },
+        FieldType::TextPrivate => "*".repeat(field.values.iter().map(String::len).sum()),
+        FieldType::ListSingle | FieldType::ListMulti => {
+            let values = field.values.iter().map(|value| option_label(field, value)).collect::<Vec<_>>();
+            let options = field.options.iter().map(|option| option.value.clone()).collect::<Vec<_>>();
+            format!("{} ({})", values.join(", "), options.join("|"))
+        },
+        _ => field.values.join(", "),
+    }
+}
+
+fn find_option(field: &Field, input: &str) -> Result<String, String> {
+    if field.options.is_empty() {
+        return Ok(input.to_string());
+    }
+
+    match field.options.iter().find(|option| option.value == input || option.label.as_ref().map(String::as_str) == Some(input)) {
+        Some(option) => Ok(option.value.clone()),
+        None => Err(format!("Unknown option {}", input)),
+    }
+}
+
+/// Apply what the user typed to a field. For booleans and single lists an empty input switches
+/// to the next value, for multiple lists the typed option is toggled.
+pub fn apply_input(field: &mut Field, input: &str) -> Result<(), String> {
+    let input = input.trim();
+
+    match field.type_ {
+        FieldType::Fixed | FieldType::Hidden => return Err(format!("Field is read-only")),
+        FieldType::Boolean => {
+            let value = match input {
+                "" => !is_true(field),
+                "1" | "true" | "yes" | "on" => true,
+                "0" | "false" | "no" | "off" => false,
+                _ => return Err(format!("Expected yes or no")),
+            };
+            field.values = vec![String::from(if value { "1" } else { "0" })];
+        },
+        FieldType::ListSingle => {
+            let value = match input {
+                "" => {
+                    let current = field.options.iter().position(|option| field.values.first() == Some(&option.value));
+                    match current {
+                        Some(index) => field.options[(index + 1) % field.options.len()].value.clone(),
+                        None => match field.options.first() {
+                            Some(option) => option.value.clone(),
+                            None => return Ok(()),
+                        },
+                    }
+                },
+                input => find_option(field, input)?,
+            };
+            field.values = vec![value];
+        },
+        FieldType::ListMulti => {
+            if input.is_empty() {
+                return Err(format!("Type an option to toggle it"));
+            }
+
+            let value = find_option(field, input)?;
+            match field.values.iter().position(|existing| existing == &value) {
+                Some(index) => { field.values.remove(index); },
+                None => field.values.push(value),
+            }
+        },
+        FieldType::JidSingle | FieldType::JidMulti => {
+            let mut values = Vec::new();
+            for jid in input.split(|c: char| c == ',' || c.is_whitespace()).filter(|jid| !jid.is_empty()) {
+                match Jid::from_str(jid) {
+                    Ok(jid) => values.push(jid.to_string()),
+                    Err(err) => return Err(format!("Invalid JID {}: {}", jid, err)),
+                }
+            }
+            if field.type_ == FieldType::JidSingle && values.len() > 1 {
+                return Err(format!("Only one JID expected"));
+            }
+            field.values = values;
+        },
+        FieldType::TextSingle | FieldType::TextPrivate | FieldType::TextMulti => {
+            field.values = match input {
+                "" => Vec::new(),
+                input => vec![input.to_string()],
+            };
+        },
+    }
+
+    Ok(())
+}
+
+/// Form to send back with the values filled by the user
+pub fn submission(form: &DataForm) -> DataForm {
+    DataForm {
+        type_: DataFormType::Submit,
+        form_type: form.form_type.clone(),
+        title: None,
+        instructions: None,
+        fields: form.fields.iter().filter(|field| field.type_ != FieldType::Fixed).map(|field| Field {
+            var: field.var.clone(),
+            type_: field.type_.clone(),
+            label: None,
+            required: false,
+            options: Vec::new(),
+            values: field.values.clone(),
+            media: Vec::new(),
+        }).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::data_forms::Option_;
+
+    fn field(type_: FieldType, values: &[&str], options: &[&str]) -> Field {
+        Field {
+            var: String::from("var"),
+            type_: type_,
+            label: None,
+            required: false,
+            options: options.iter().map(|option| Option_ { label: None, value: option.to_string() }).collect(),
+            values: values.iter().map(|value| value.to_string()).collect(),
+            media: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_boolean_toggles_on_empty_input() {
+        let mut boolean = field(FieldType::Boolean, &["0"], &[]);
+
+        apply_input(&mut boolean, "").unwrap();
+        assert_eq!(boolean.values, vec!["1".to_string()]);
+
+        apply_input(&mut boolean, "no").unwrap();
+        assert_eq!(boolean.values, vec!["0".to_string()]);
+
+        assert!(apply_input(&mut boolean, "maybe").is_err());
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut single = field(FieldType::ListSingle, &["a"], &["a", "b"]);
+        apply_input(&mut single, "").unwrap();
+        assert_eq!(single.values, vec!["b".to_string()]);
+        apply_input(&mut single, "").unwrap();
+        assert_eq!(single.values, vec!["a".to_string()]);
+        assert!(apply_input(&mut single, "c").is_err());
+
+        let mut multi = field(FieldType::ListMulti, &["a"], &["a", "b"]);
+        apply_input(&mut multi, "b").unwrap();
+        apply_input(&mut multi, "a").unwrap();
+        assert_eq!(multi.values, vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn test_jid_fields_are_validated() {
+        let mut jids = field(FieldType::JidMulti, &[], &[]);
+        apply_input(&mut jids, "a@server.tld, b@server.tld").unwrap();
+        assert_eq!(jids.values, vec!["a@server.tld".to_string(), "b@server.tld".to_string()]);
+
+        let mut jid = field(FieldType::JidSingle, &[], &[]);
+        assert!(apply_input(&mut jid, "a@server.tld b@server.tld").is_err());
+    }
+}
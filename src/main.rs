@@ -6,6 +6,10 @@ extern crate log;
 extern crate simple_logging;
 extern crate tokio;
 extern crate tokio_xmpp;
+extern crate tokio_tls;
+extern crate native_tls;
+extern crate sasl;
+extern crate trust_dns_resolver;
 extern crate xmpp_parsers;
 extern crate rpassword;
 extern crate futures;
@@ -15,83 +19,318 @@ extern crate tokio_file_unix;
 extern crate dirs;
 extern crate signal_hook;
 
-use chrono::Utc;
+use chrono::{Local, TimeZone, Utc};
 use futures::{future, Future, Sink, Stream};
 use log::LevelFilter;
 use signal_hook::iterator::Signals;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::rc::Rc;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tokio::runtime::current_thread::Runtime;
-use tokio_xmpp::{Client, Error as XmppError};
+use tokio::timer::Delay;
+use tokio_xmpp::{Error as XmppError, Packet};
 use uuid::Uuid;
 use xmpp_parsers::iq::Iq;
-use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType};
-use xmpp_parsers::muc::Muc;
-use xmpp_parsers::presence::{Presence, Show as PresenceShow, Type as PresenceType};
-use xmpp_parsers::{Element, Jid};
+use xmpp_parsers::mam::Result_ as MamResult;
+use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType, Subject as XmppParsersSubject};
+use xmpp_parsers::muc::user::{Affiliation as MucAffiliation, Role as MucRole};
+use xmpp_parsers::presence::{Presence, Type as PresenceType};
+use xmpp_parsers::pubsub::PubSubEvent;
+use xmpp_parsers::roster::{Ask as RosterAsk, Group as RosterGroup, Item as RosterItem, Subscription as RosterSubscription};
+use xmpp_parsers::stanza_error::StanzaError;
+use xmpp_parsers::{BareJid, Element, FullJid, Jid};
 
 mod core;
+mod connection;
 mod config;
 mod account;
 mod contact;
 mod conversation;
+mod muc;
+mod form;
 mod message;
 mod command;
 mod terminus;
 mod plugins;
 
-use crate::core::{Aparte, Plugin, Event, CommandOrMessage};
+use crate::connection::Session;
+use crate::core::{Aparte, Plugin, Event, CommandOrMessage, redacted, stanza_error_text};
 use crate::message::{Message};
 use crate::command::{CommandParser, Command};
 
-fn handle_stanza(aparte: Rc<Aparte>, stanza: Element) {
+fn handle_stanza(aparte: Rc<Aparte>, account: &FullJid, stanza: Element) {
     if let Some(message) = XmppParsersMessage::try_from(stanza.clone()).ok() {
-        handle_message(aparte, message);
+        handle_message(aparte, account, message);
     } else if let Some(iq) = Iq::try_from(stanza.clone()).ok() {
-        Rc::clone(&aparte).event(Event::Iq(iq));
+        if !aparte.handle_iq_response(account, &iq) {
+            Rc::clone(&aparte).event(Event::Iq(account.clone(), iq));
+        }
     } else if let Some(presence) = Presence::try_from(stanza.clone()).ok() {
-        Rc::clone(&aparte).event(Event::Presence(presence));
+        Rc::clone(&aparte).event(Event::Presence(account.clone(), presence));
     }
 }
 
-fn handle_message(aparte: Rc<Aparte>, message: XmppParsersMessage) {
+/// Prefix of the ids of the messages changing a channel subject
+const SUBJECT_ID_PREFIX: &str = "subject-";
+
+fn handle_message(aparte: Rc<Aparte>, account: &FullJid, message: XmppParsersMessage) {
+    if message.type_ == XmppParsersMessageType::Groupchat && message.bodies.is_empty() {
+        if let (Some(from), Some((_, subject))) = (message.from.as_ref(), message.get_best_subject(vec![])) {
+            let (channel, by) = match from {
+                Jid::Full(from) => (from.clone().into(), Some(from.resource.clone())),
+                Jid::Bare(from) => (from.clone(), None),
+            };
+            let subject = match subject.0.is_empty() {
+                true => None,
+                false => Some(subject.0.clone()),
+            };
+            Rc::clone(&aparte).event(Event::Subject { account: account.clone(), channel: channel, subject: subject, by: by });
+        }
+    }
+
+    // Servers don't always echo the subject in the error, the id of our request tells it too
+    let subject_error = !message.subjects.is_empty() || message.id.as_ref().map_or(false, |id| id.starts_with(SUBJECT_ID_PREFIX));
+    if message.type_ == XmppParsersMessageType::Error && subject_error {
+        if let Some(from) = message.from.as_ref() {
+            let error = message.payloads.iter().find_map(|payload| StanzaError::try_from(payload.clone()).ok());
+            let text = match error {
+                Some(error) => format!("Cannot change the subject: {}", stanza_error_text(&error)),
+                None => format!("Cannot change the subject"),
+            };
+            Rc::clone(&aparte).event(Event::Notice { account: account.clone(), conversation: from.clone().into(), text: text });
+        }
+    }
+
+    if let Some(from) = message.from.as_ref() {
+        for payload in message.payloads.iter() {
+            if let Ok(event) = PubSubEvent::try_from(payload.clone()) {
+                Rc::clone(&aparte).event(Event::PubSub { account: account.clone(), from: from.clone(), event: event });
+            }
+        }
+    }
+
+    for payload in message.payloads.iter() {
+        if let Ok(result) = MamResult::try_from(payload.clone()) {
+            Rc::clone(&aparte).event(Event::Archive { account: account.clone(), from: message.from.clone(), result: result });
+        }
+    }
+
+    if let Some(message) = plugins::carbons::CarbonsPlugin::carbon_message(account, &message) {
+        Rc::clone(&aparte).event(Event::Message(Some(account.clone()), message));
+    }
+
+    // Offline messages and channel history keep the time they were originally sent at
+    let timestamp = message::stanza_timestamp(&message).unwrap_or_else(Utc::now);
+
     if let (Some(from), Some(to)) = (message.from, message.to) {
         if let Some(ref body) = message.bodies.get("") {
             match message.type_ {
                 XmppParsersMessageType::Error => {},
                 XmppParsersMessageType::Chat => {
                     let id = message.id.unwrap_or_else(|| Uuid::new_v4().to_string());
-                    let timestamp = Utc::now();
                     let message = Message::incoming_chat(id, timestamp, &from, &to, &body.0);
-                    Rc::clone(&aparte).event(Event::Message(message));
+                    Rc::clone(&aparte).event(Event::Message(Some(account.clone()), message));
                 },
                 XmppParsersMessageType::Groupchat => {
                     let id = message.id.unwrap_or_else(|| Uuid::new_v4().to_string());
-                    let timestamp = Utc::now();
                     let message = Message::incoming_groupchat(id, timestamp, &from, &to, &body.0);
-                    Rc::clone(&aparte).event(Event::Message(message));
+                    Rc::clone(&aparte).event(Event::Message(Some(account.clone()), message));
                 },
                 _ => {},
             }
         }
+    }
+}
 
-        for payload in message.payloads {
-            if let Some(received) = xmpp_parsers::carbons::Received::try_from(payload).ok() {
-                if let Some(ref original) = received.forwarded.stanza {
-                    if original.type_ != XmppParsersMessageType::Error {
-                        if let (Some(from), Some(to)) = (original.from.as_ref(), original.to.as_ref()) {
-                            if let Some(body) = original.bodies.get("") {
-                                let id = original.id.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
-                                let timestamp = Utc::now();
-                                let message = Message::incoming_chat(id, timestamp, &from, &to, &body.0);
-                                Rc::clone(&aparte).event(Event::Message(message));
-                            }
-                        }
+const RECONNECT_DELAY_MIN: u64 = 1;
+const RECONNECT_DELAY_MAX: u64 = 300;
+
+fn reconnect_delay(attempt: u32) -> Duration {
+    let delay = RECONNECT_DELAY_MIN.checked_shl(attempt).unwrap_or(RECONNECT_DELAY_MAX);
+    Duration::from_secs(cmp::min(delay, RECONNECT_DELAY_MAX))
+}
+
+/// Connect an account and reconnect it whenever the connection is lost, until this connection
+/// `generation` is replaced by another `/connect` or cancelled
+fn start_connection(aparte: Rc<Aparte>, account: FullJid, server: Option<String>, port: Option<u16>, password: String, generation: u64, attempt: u32) {
+    Rc::clone(&aparte).log(format!("Connecting to {}", account));
+    let resume = aparte.resumption(&account);
+
+    // Account of the session once online, the server may bind another resource than the requested one
+    let online: Rc<RefCell<Option<FullJid>>> = Rc::new(RefCell::new(None));
+
+    let session_aparte = Rc::clone(&aparte);
+    let session_account = account.clone();
+    let session_online = Rc::clone(&online);
+    let client = connection::connect(account.clone(), server.clone(), port, password.clone(), resume).and_then(move |(stream, session)| {
+        let aparte = session_aparte;
+        let account = match &session {
+            Session::New { jid, .. } => jid.clone(),
+            Session::Resumed(_) => session_account,
+        };
+        if !aparte.is_current_generation(&account, generation) {
+            // Replaced while connecting, dropping the stream closes it
+            return future::Either::A(future::ok(()));
+        }
+
+        let features = stream.stream_features.clone();
+        let (sink, stream) = stream.split();
+        let (tx, rx) = futures::unsync::mpsc::unbounded();
+
+        Rc::clone(&aparte).add_connection(account.clone(), tx, features);
+
+        tokio::runtime::current_thread::spawn(
+            rx.forward(
+                sink.sink_map_err(|e| warn!("Send error: {:?}", e))
+                ).map(|(rx, mut sink)| {
+                drop(rx);
+                let _ = sink.close();
+            })
+            );
+
+        session_online.replace(Some(account.clone()));
+        match session {
+            Session::Resumed(h) => {
+                Rc::clone(&aparte).log(format!("Resumed session of {}", account));
+                Rc::clone(&aparte).resume_session(&account, h);
+                Rc::clone(&aparte).event(Event::Resumed(account.clone()));
+            },
+            Session::New { stream_management, .. } => {
+                Rc::clone(&aparte).log(format!("Connected as {}", account));
+                aparte.new_session(&account, stream_management);
+
+                Rc::clone(&aparte).event(Event::Connected(account.clone()));
+            },
+        }
+
+        future::Either::B(stream.map_err(XmppError::from).for_each(move |packet| {
+            if !aparte.is_current_generation(&account, generation) {
+                return Err(XmppError::Disconnected);
+            }
+
+            match packet {
+                Packet::Stanza(stanza) => {
+                    debug!("RECV: {}", redacted(&stanza));
+
+                    if stanza.ns() == Some(String::from(xmpp_parsers::ns::SM)) {
+                        Rc::clone(&aparte).handle_stream_management(&account, stanza);
+                    } else {
+                        aparte.stanza_received(&account);
+                        handle_stanza(Rc::clone(&aparte), &account, stanza);
                     }
-                }
+                },
+                Packet::StreamEnd => return Err(XmppError::Disconnected),
+                _ => {},
             }
+
+            Ok(())
+        }))
+    });
+
+    let client = client.then(move |result| {
+        // The connection was replaced, the new one took over the account
+        if !aparte.is_current_generation(&account, generation) {
+            return Ok(());
+        }
+
+        // Reconnect with the bound resource, its stream management session can be resumed
+        let bound = online.borrow().clone();
+        let account = bound.clone().unwrap_or(account);
+
+        let reconnect = match result {
+            Ok(()) | Err(XmppError::Disconnected) => {
+                Rc::clone(&aparte).log(format!("Connection to {} lost", account));
+                true
+            },
+            Err(XmppError::Auth(auth)) => {
+                Rc::clone(&aparte).log(format!("Authentication failed {}", auth));
+                false
+            },
+            Err(error) => {
+                Rc::clone(&aparte).log(format!("Connection error {:?}", error));
+                true
+            },
+        };
+
+        aparte.remove_connection(&account);
+        if bound.is_some() {
+            Rc::clone(&aparte).event(Event::Disconnected(account.clone()));
+        }
+
+        if reconnect {
+            let attempt = match bound {
+                Some(_) => 0,
+                None => attempt + 1,
+            };
+            let delay = reconnect_delay(attempt);
+            Rc::clone(&aparte).log(format!("Reconnecting to {} in {}s", account, delay.as_secs()));
+
+            tokio::runtime::current_thread::spawn(Delay::new(Instant::now() + delay).then(move |_| {
+                if aparte.is_current_generation(&account, generation) {
+                    start_connection(aparte, account, server, port, password, generation, attempt);
+                }
+                Ok(())
+            }));
         }
+
+        Ok(())
+    });
+
+    tokio::runtime::current_thread::spawn(client);
+}
+
+/// Account the user is currently acting on: the one bound to the current window, or the last
+/// connected one when the window isn't bound to any account (e.g. console).
+fn current_account(aparte: &Aparte) -> Option<FullJid> {
+    let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+    ui.current_account().or_else(|| aparte.current_connection())
+}
+
+/// Account and channel of the current window, when it is a channel
+fn current_channel(aparte: &Aparte) -> Result<(FullJid, BareJid), String> {
+    let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+    match (ui.current_account(), ui.current_channel()) {
+        (Some(account), Some(channel)) => Ok((account, channel)),
+        _ => Err(format!("Current window is not a channel")),
+    }
+}
+
+/// Nicks of the occupants of the current channel
+fn current_occupants(aparte: &Aparte) -> Vec<String> {
+    let (account, channel) = match current_channel(aparte) {
+        Ok(current) => current,
+        Err(_) => return Vec::new(),
+    };
+
+    let conversation = aparte.get_plugin::<plugins::conversation::ConversationPlugin>().unwrap();
+    match conversation.channel(&account, &channel) {
+        Some(channel) => channel.occupants.keys().cloned().collect(),
+        None => Vec::new(),
+    }
+}
+
+fn occupant_nick(aparte: &Aparte, account: &FullJid, channel: &BareJid, nick: &str) -> Result<(), String> {
+    let conversation = aparte.get_plugin::<plugins::conversation::ConversationPlugin>().unwrap();
+    match conversation.channel(account, channel) {
+        Some(channel) if channel.occupants.contains_key(nick) => Ok(()),
+        _ => Err(format!("Unknown occupant {}", nick)),
+    }
+}
+
+/// Real JID designated by either a JID or the nick of an occupant
+fn occupant_jid(aparte: &Aparte, account: &FullJid, channel: &BareJid, occupant: &str) -> Result<Jid, String> {
+    let conversation = aparte.get_plugin::<plugins::conversation::ConversationPlugin>().unwrap();
+    match conversation.channel(account, channel).and_then(|channel| channel.occupants.get(occupant)) {
+        Some(known) => match &known.jid {
+            Some(jid) => Ok(Jid::Bare(jid.clone())),
+            None => Err(format!("Real JID of {} is not visible in this channel", occupant)),
+        },
+        None if occupant.contains('@') => Jid::from_str(occupant).map_err(|err| format!("Invalid JID {}: {}", occupant, err)),
+        None => Err(format!("Unknown occupant {}", occupant)),
     }
 }
 
@@ -102,7 +341,10 @@ command_def!{
   account       Account to connect to
 
 Description:
-  Connect to the given account.
+  Connect to the given account. Server and port configured for the account are used instead of
+  the SRV records, a port given after the account overrides the configured one. Port 5223 means
+  direct TLS. Connecting an account already connected or waiting to reconnect replaces that
+  connection.
 
 Examples:
   /connect account@server.tld
@@ -113,69 +355,108 @@ Examples:
             aparte.config.accounts.iter().map(|(_, account)| account.login.clone()).collect()
         }
     },
-    (password) password,
-    |aparte, _command| {
-        if let Ok(jid) = Jid::from_str(&account) {
-            let full_jid = match jid {
-                Jid::Full(jid) => jid,
-                Jid::Bare(jid) => jid.with_resource("aparte"),
-            };
-            Rc::clone(&aparte).log(format!("Connecting to {}", account));
-            let client = Client::new(&full_jid.to_string(), &password).unwrap();
-
-            let (sink, stream) = client.split();
-            let (tx, rx) = futures::unsync::mpsc::unbounded();
+    (optional) password,
+    |aparte, command| {
+        let (account, port) = match account.rfind(':') {
+            Some(index) => match account[index + 1..].parse::<u16>() {
+                Ok(port) => (&account[..index], Some(port)),
+                Err(_) => (&account[..], None),
+            },
+            None => (&account[..], None),
+        };
 
-            Rc::clone(&aparte).add_connection(full_jid.clone(), tx);
+        let config = aparte.config.accounts.iter()
+            .find(|(name, config)| name.as_str() == account || config.login == account)
+            .map(|(_, config)| config.clone());
 
-            tokio::runtime::current_thread::spawn(
-                rx.forward(
-                    sink.sink_map_err(|_| panic!("Pipe"))
-                    ).map(|(rx, mut sink)| {
-                    drop(rx);
-                    let _ = sink.close();
-                }).map_err(|e| {
-                        panic!("Send error: {:?}", e);
-                    })
-                );
+        let login = match &config {
+            Some(config) => config.login.clone(),
+            None => account.to_string(),
+        };
 
-            let event_aparte = Rc::clone(&aparte);
-            let client = stream.for_each(move |event| {
-                if event.is_online() {
-                    Rc::clone(&event_aparte).log(format!("Connected as {}", account));
+        let password = match (password, &config) {
+            (Some(password), _) => password,
+            (None, Some(config)) => {
+                // Connect again once the credential source gave the password
+                let mut args = command.args.clone();
+                let command = command.clone();
+                tokio::runtime::current_thread::spawn(config.password_in_background().then(move |result| {
+                    match result {
+                        Ok(Some(password)) => {
+                            args.truncate(2);
+                            args.push(password);
+                            if let Err(err) = Rc::clone(&aparte).parse_command(Command::new(args)) {
+                                aparte.log(err);
+                            }
+                        },
+                        Ok(None) => read_password(aparte, &login, command),
+                        Err(err) => {
+                            Rc::clone(&aparte).log(err);
+                            read_password(aparte, &login, command);
+                        },
+                    }
+                    Ok(())
+                }));
+                return Ok(());
+            },
+            (None, None) => {
+                read_password(aparte, &login, command.clone());
+                return Ok(());
+            },
+        };
 
-                    Rc::clone(&event_aparte).event(Event::Connected(full_jid.clone()));
+        if let Ok(jid) = Jid::from_str(&login) {
+            let full_jid = match jid {
+                Jid::Full(jid) => jid,
+                Jid::Bare(jid) => jid.with_resource("aparte"),
+            };
 
-                    let mut presence = Presence::new(PresenceType::None);
-                    presence.show = Some(PresenceShow::Chat);
+            let server = config.as_ref().and_then(|config| config.server.clone());
+            let port = port.or_else(|| config.as_ref().and_then(|config| config.port));
 
-                    event_aparte.send(presence.into());
-                } else if let Some(stanza) = event.into_stanza() {
-                    debug!("RECV: {}", String::from(&stanza));
+            // A new connection replaces the one of the account or its scheduled reconnection
+            let generation = aparte.new_connection_generation(&full_jid);
+            let bare: BareJid = full_jid.clone().into();
+            for previous in aparte.accounts().into_iter().filter(|previous| BareJid::from(Jid::Full(previous.clone())) == bare) {
+                aparte.remove_connection(&previous);
+                Rc::clone(&aparte).event(Event::Disconnected(previous));
+            }
 
-                    handle_stanza(Rc::clone(&event_aparte), stanza);
-                }
+            start_connection(aparte, full_jid, server, port, password, generation, 0);
 
-                future::ok(())
-            });
+            Ok(())
+        } else {
+            Err(format!("Invalid JID {}", login))
+        }
+    }
+}
 
-            let error_aparte = Rc::clone(&aparte);
-            let client = client.map_err(move |error| {
-                match error {
-                    XmppError::Auth(auth) => {
-                        Rc::clone(&error_aparte).log(format!("Authentication failed {}", auth));
-                    },
-                    error => {
-                        Rc::clone(&error_aparte).log(format!("Connection error {:?}", error));
-                    },
-                }
-            });
+/// Ask the user for the password of an account, the command is run again with it
+fn read_password(aparte: Rc<Aparte>, login: &str, command: Command) {
+    Rc::clone(&aparte).log(format!("Password for {}", login));
+    aparte.event(Event::ReadPassword(command));
+}
 
-            tokio::runtime::current_thread::spawn(client);
+/// Connect accounts configured with `autoconnect` with the passwords resolved before the UI
+/// started, the password is prompted for when they have no credential source or it failed
+fn autoconnect(aparte: Rc<Aparte>, passwords: HashMap<String, Result<Option<String>, String>>) {
+    for (name, account) in aparte.config.accounts.iter().filter(|(_, account)| account.autoconnect) {
+        let mut args = vec!["connect".to_string(), name.clone()];
+        match passwords.get(name) {
+            Some(Ok(Some(password))) => args.push(password.clone()),
+            Some(Err(err)) => {
+                Rc::clone(&aparte).log(err.clone());
+                read_password(Rc::clone(&aparte), &account.login, Command::new(args));
+                continue;
+            },
+            _ => {
+                read_password(Rc::clone(&aparte), &account.login, Command::new(args));
+                continue;
+            },
+        }
 
-            Ok(())
-        } else {
-            Err(format!("Invalid JID {}", account))
+        if let Err(err) = Rc::clone(&aparte).parse_command(Command::new(args)) {
+            Rc::clone(&aparte).log(err);
         }
     }
 }
@@ -222,28 +503,31 @@ Example:
     contact: {
         completion: |aparte, _command| {
             let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
-            contact.contacts.iter().map(|c| c.0.to_string()).collect()
+            contact.jids()
         }
     },
     (optional) message,
     |aparte, _command| {
-        match aparte.current_connection() {
-            Some(connection) => {
+        match current_account(&aparte) {
+            Some(account) => {
                 match Jid::from_str(&contact.clone()) {
                     Ok(jid) => {
                         let to = match jid.clone() {
                             Jid::Bare(jid) => jid,
                             Jid::Full(jid) => jid.into(),
                         };
-                        Rc::clone(&aparte).event(Event::Chat(to));
+                        Rc::clone(&aparte).event(Event::Chat {
+                            account: account.clone(),
+                            contact: to,
+                        });
                         if message.is_some() {
                             let id = Uuid::new_v4().to_string();
-                            let from: Jid = connection.into();
+                            let from: Jid = account.clone().into();
                             let timestamp = Utc::now();
                             let message = Message::outgoing_chat(id, timestamp, &from, &jid, &message.unwrap());
-                            Rc::clone(&aparte).event(Event::Message(message.clone()));
+                            Rc::clone(&aparte).event(Event::Message(Some(account.clone()), message.clone()));
 
-                            aparte.send(Element::try_from(message).unwrap());
+                            aparte.send(&account, Element::try_from(message).unwrap());
                         }
                         Ok(())
                     },
@@ -261,35 +545,42 @@ Example:
 
 command_def!{
     join,
-    r#"/join <channel>
+    r#"/join <channel> [<nick>] [<password>]
 
   channel       Channel JID to join
+  nick          Optional nick, the account nick by default
+  password      Optional password of the channel
+
 Description:
-  Open a window and join a given channel.
+  Open a window and join a given channel. If the nick is already used in the
+  channel an alternate one is tried.
 
-Example:
-  /join channel@conference.server.tld"#,
-    muc,
+Examples:
+  /join channel@conference.server.tld
+  /join channel@conference.server.tld/nick
+  /join channel@conference.server.tld nick secret"#,
+    muc: {
+        completion: |aparte, _command| {
+            aparte.get_plugin::<plugins::bookmarks::BookmarksPlugin>().unwrap().channels()
+        }
+    },
+    (optional) nick,
+    (optional) password,
     |aparte, _command| {
-        match aparte.current_connection() {
-            Some(connection) => {
+        match current_account(&aparte) {
+            Some(account) => {
                 match Jid::from_str(&muc) {
                     Ok(jid) => {
-                        let to = match jid {
-                            Jid::Full(jid) => jid,
-                            Jid::Bare(jid) => {
-                                let node = connection.node.clone().unwrap();
-                                jid.with_resource(node)
+                        let to = match (jid, nick) {
+                            (jid, Some(nick)) => BareJid::from(jid).with_resource(nick),
+                            (Jid::Full(jid), None) => jid,
+                            (Jid::Bare(jid), None) => {
+                                let nick = plugins::conversation::ConversationPlugin::default_nick(&aparte, &account);
+                                jid.with_resource(nick)
                             }
                         };
-                        let from: Jid = connection.into();
 
-                        let mut presence = Presence::new(PresenceType::None);
-                        presence = presence.with_to(Jid::Full(to.clone()));
-                        presence = presence.with_from(from);
-                        presence.add_payload(Muc::new());
-                        aparte.send(presence.into());
-                        aparte.event(Event::Join(to.clone()));
+                        plugins::conversation::ConversationPlugin::join(aparte, &account, to, password);
 
                         Ok(())
                     },
@@ -305,6 +596,706 @@ Example:
     }
 }
 
+command_def!{
+    bookmark,
+    r#"/bookmark <action> [<channel>]
+
+  action        add, remove or list
+  channel       Channel JID, the current channel by default
+
+Description:
+  Manage the bookmarks of the current account. They are stored on the server
+  and shared with other clients. Bookmarked channels are joined automatically
+  on connection.
+
+Examples:
+  /bookmark add
+  /bookmark add channel@conference.server.tld
+  /bookmark remove channel@conference.server.tld
+  /bookmark list"#,
+    action: {
+        completion: |_aparte, _command| {
+            vec!["add".to_string(), "remove".to_string(), "list".to_string()]
+        }
+    },
+    (optional) channel: {
+        completion: |aparte, _command| {
+            aparte.get_plugin::<plugins::bookmarks::BookmarksPlugin>().unwrap().channels()
+        }
+    },
+    |aparte, _command| {
+        let (account, jid) = match channel {
+            Some(channel) => match BareJid::from_str(&channel) {
+                Ok(jid) => (current_account(&aparte).ok_or(format!("No connection found"))?, Some(jid)),
+                Err(err) => return Err(format!("Invalid JID {}: {}", channel, err)),
+            },
+            None => match current_channel(&aparte) {
+                Ok((account, jid)) => (account, Some(jid)),
+                Err(_) => (current_account(&aparte).ok_or(format!("No connection found"))?, None),
+            },
+        };
+
+        match action.as_str() {
+            "add" => {
+                let jid = jid.ok_or(format!("Missing channel argument"))?;
+                let (nick, password) = match aparte.get_plugin::<plugins::conversation::ConversationPlugin>().unwrap().channel(&account, &jid) {
+                    Some(channel) => (Some(channel.nick.clone()), channel.password.clone()),
+                    None => (None, None),
+                };
+                let name = aparte.get_plugin::<plugins::bookmarks::BookmarksPlugin>().unwrap().bookmark(&account, &jid).and_then(|bookmark| bookmark.name);
+                let bookmark = plugins::bookmarks::Bookmark {
+                    jid: jid.clone(),
+                    name: name,
+                    nick: nick,
+                    password: password,
+                    autojoin: true,
+                };
+                plugins::bookmarks::BookmarksPlugin::update(aparte, &account, &jid, Some(bookmark))
+            },
+            "remove" => {
+                let jid = jid.ok_or(format!("Missing channel argument"))?;
+                if aparte.get_plugin::<plugins::bookmarks::BookmarksPlugin>().unwrap().bookmark(&account, &jid).is_none() {
+                    return Err(format!("{} is not bookmarked", jid));
+                }
+                plugins::bookmarks::BookmarksPlugin::update(aparte, &account, &jid, None)
+            },
+            "list" => {
+                let bookmarks = aparte.get_plugin::<plugins::bookmarks::BookmarksPlugin>().unwrap().bookmarks(&account);
+                match bookmarks.is_empty() {
+                    true => Rc::clone(&aparte).log(format!("No bookmarks")),
+                    false => for bookmark in bookmarks {
+                        Rc::clone(&aparte).log(bookmark.to_string());
+                    },
+                }
+                Ok(())
+            },
+            action => Err(format!("Unknown action {}", action)),
+        }
+    }
+}
+
+command_def!{
+    search,
+    r#"/search <text>
+
+  text          Text to look for, case insensitive
+
+Description:
+  Search the messages stored locally, in every conversation.
+
+Example:
+  /search "release date""#,
+    text,
+    |aparte, _command| {
+        let search = aparte.get_plugin::<plugins::history::HistoryPlugin>().unwrap().search(&text);
+        let error_aparte = Rc::clone(&aparte);
+        tokio::runtime::current_thread::spawn(search.map(move |results| {
+            if results.is_empty() {
+                Rc::clone(&aparte).log(format!("No message found"));
+            }
+            for (conversation, message) in results {
+                let timestamp = Local.from_utc_datetime(&message.timestamp().naive_utc());
+                Rc::clone(&aparte).log(format!("{} {} {}: {}", timestamp.format("%F %T"), conversation, message.author(), message.body()));
+            }
+        }).map_err(move |err| error_aparte.log(err)));
+
+        Ok(())
+    }
+}
+
+command_def!{
+    leave,
+    r#"/leave [<reason>]
+
+  reason        Optional message shown to the other occupants
+
+Description:
+  Leave the current channel and close its window.
+
+Examples:
+  /leave
+  /leave "See you""#,
+    (optional) reason,
+    |aparte, _command| {
+        let (account, channel) = current_channel(&aparte)?;
+        plugins::conversation::ConversationPlugin::leave(&aparte, &account, &channel, reason);
+        let window = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap().conversation_window(&account, &channel);
+        if let Some(window) = window {
+            aparte.event(Event::Close(window));
+        }
+
+        Ok(())
+    }
+}
+
+command_def!{
+    close,
+    r#"/close
+
+Description:
+  Close the current window, leaving the channel it shows if any. The console
+  cannot be closed."#,
+    |aparte, _command| {
+        let window = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap().current_window();
+        let window = match window {
+            Some(window) if window == "console" => return Err(format!("The console cannot be closed")),
+            Some(window) => window,
+            None => return Err(format!("No window to close")),
+        };
+
+        if let Ok((account, channel)) = current_channel(&aparte) {
+            plugins::conversation::ConversationPlugin::leave(&aparte, &account, &channel, None);
+        }
+        aparte.event(Event::Close(window));
+
+        Ok(())
+    }
+}
+
+command_def!{
+    topic,
+    r#"/topic [<subject>]
+
+  subject       New subject of the channel
+
+Description:
+  Show the subject of the current channel, or change it when the channel
+  allows it.
+
+Examples:
+  /topic
+  /topic "Release planning""#,
+    (optional) subject,
+    |aparte, _command| {
+        let (account, channel) = current_channel(&aparte)?;
+
+        let current = {
+            let conversation = aparte.get_plugin::<plugins::conversation::ConversationPlugin>().unwrap();
+            match conversation.channel(&account, &channel) {
+                Some(channel) => channel.subject.clone(),
+                None => return Err(format!("Not in channel {}", channel)),
+            }
+        };
+
+        match subject {
+            None => {
+                let text = match current {
+                    Some(current) => format!("Subject: {}", current),
+                    None => format!("No subject"),
+                };
+                aparte.event(Event::Notice { account: account, conversation: channel, text: text });
+            },
+            Some(subject) => {
+                // Whether occupants may change it depends on the room configuration, the server
+                // tells with an error
+                let mut message = XmppParsersMessage::new(Some(Jid::Bare(channel)));
+                message.id = Some(format!("{}{}", SUBJECT_ID_PREFIX, Uuid::new_v4().to_hyphenated()));
+                message.type_ = XmppParsersMessageType::Groupchat;
+                message.subjects.insert(String::new(), XmppParsersSubject(subject));
+                aparte.send(&account, message.into());
+            },
+        }
+
+        Ok(())
+    }
+}
+
+command_def!{
+    kick,
+    r#"/kick <nick> [<reason>]
+
+  nick          Occupant to kick
+  reason        Optional reason given to the occupant
+
+Description:
+  Kick an occupant out of the current channel. Requires the moderator role.
+
+Examples:
+  /kick troll
+  /kick troll "Stop spamming""#,
+    nick: {
+        completion: |aparte, _command| {
+            current_occupants(&aparte)
+        }
+    },
+    (optional) reason,
+    |aparte, _command| {
+        let (account, channel) = current_channel(&aparte)?;
+        occupant_nick(&aparte, &account, &channel, &nick)?;
+
+        let item = muc::AdminItem::role(&nick, MucRole::None, reason);
+        plugins::conversation::ConversationPlugin::admin(aparte, &account, &channel, item, format!("kick {}", nick));
+
+        Ok(())
+    }
+}
+
+command_def!{
+    ban,
+    r#"/ban <occupant> [<reason>]
+
+  occupant      Nick or JID to ban
+  reason        Optional reason given to the occupant
+
+Description:
+  Ban a user from the current channel. A nick can only be used when its real
+  JID is visible to you. Requires the admin or owner affiliation.
+
+Examples:
+  /ban troll
+  /ban troll@server.tld "Stop spamming""#,
+    occupant: {
+        completion: |aparte, _command| {
+            current_occupants(&aparte)
+        }
+    },
+    (optional) reason,
+    |aparte, _command| {
+        let (account, channel) = current_channel(&aparte)?;
+        let jid = occupant_jid(&aparte, &account, &channel, &occupant)?;
+
+        let item = muc::AdminItem::affiliation(jid, MucAffiliation::Outcast, reason);
+        plugins::conversation::ConversationPlugin::admin(aparte, &account, &channel, item, format!("ban {}", occupant));
+
+        Ok(())
+    }
+}
+
+command_def!{
+    role,
+    r#"/role <nick> <role>
+
+  nick          Occupant whose role is changed
+  role          One of moderator, participant, visitor or none
+
+Description:
+  Change the role of an occupant of the current channel for the duration of
+  its visit. Requires the moderator role.
+
+Examples:
+  /role contact moderator
+  /role troll visitor"#,
+    nick: {
+        completion: |aparte, _command| {
+            current_occupants(&aparte)
+        }
+    },
+    role: {
+        completion: |_aparte, _command| {
+            vec!["moderator".to_string(), "participant".to_string(), "visitor".to_string(), "none".to_string()]
+        }
+    },
+    |aparte, _command| {
+        let (account, channel) = current_channel(&aparte)?;
+        occupant_nick(&aparte, &account, &channel, &nick)?;
+        let role = match MucRole::from_str(&role) {
+            Ok(role) => role,
+            Err(_) => return Err(format!("Unknown role {}", role)),
+        };
+
+        let item = muc::AdminItem::role(&nick, role, None);
+        plugins::conversation::ConversationPlugin::admin(aparte, &account, &channel, item, format!("change role of {}", nick));
+
+        Ok(())
+    }
+}
+
+command_def!{
+    affiliation,
+    r#"/affiliation <occupant> <affiliation>
+
+  occupant      Nick or JID whose affiliation is changed
+  affiliation   One of owner, admin, member, outcast or none
+
+Description:
+  Change the long-lived affiliation of a user with the current channel. A nick
+  can only be used when its real JID is visible to you. Requires the admin or
+  owner affiliation.
+
+Examples:
+  /affiliation contact member
+  /affiliation contact@server.tld admin"#,
+    occupant: {
+        completion: |aparte, _command| {
+            current_occupants(&aparte)
+        }
+    },
+    affiliation: {
+        completion: |_aparte, _command| {
+            vec!["owner".to_string(), "admin".to_string(), "member".to_string(), "outcast".to_string(), "none".to_string()]
+        }
+    },
+    |aparte, _command| {
+        let (account, channel) = current_channel(&aparte)?;
+        let jid = occupant_jid(&aparte, &account, &channel, &occupant)?;
+        let affiliation = match MucAffiliation::from_str(&affiliation) {
+            Ok(affiliation) => affiliation,
+            Err(_) => return Err(format!("Unknown affiliation {}", affiliation)),
+        };
+
+        let item = muc::AdminItem::affiliation(jid, affiliation, None);
+        plugins::conversation::ConversationPlugin::admin(aparte, &account, &channel, item, format!("change affiliation of {}", occupant));
+
+        Ok(())
+    }
+}
+
+command_def!{
+    voice,
+    r#"/voice <nick>
+
+  nick          Visitor to grant voice to
+
+Description:
+  Allow a visitor of the current channel to send messages by making it a
+  participant. Requires the moderator role.
+
+Example:
+  /voice contact"#,
+    nick: {
+        completion: |aparte, _command| {
+            current_occupants(&aparte)
+        }
+    },
+    |aparte, _command| {
+        let (account, channel) = current_channel(&aparte)?;
+        occupant_nick(&aparte, &account, &channel, &nick)?;
+
+        let item = muc::AdminItem::role(&nick, MucRole::Participant, None);
+        plugins::conversation::ConversationPlugin::admin(aparte, &account, &channel, item, format!("grant voice to {}", nick));
+
+        Ok(())
+    }
+}
+
+command_def!{
+    roomconfig,
+    r#"/roomconfig [<action>]
+
+  action        submit or cancel, from the configuration window
+
+Description:
+  Open the configuration form of the current channel. Select fields with
+  Up and Down and type their value followed by Enter, an empty value
+  toggles booleans and cycles through list options. Then submit or cancel
+  the form. Requires the owner affiliation.
+
+Examples:
+  /roomconfig
+  /roomconfig submit
+  /roomconfig cancel"#,
+    (optional) action: {
+        completion: |_aparte, _command| {
+            vec!["submit".to_string(), "cancel".to_string()]
+        }
+    },
+    |aparte, _command| {
+        match action.as_ref().map(String::as_str) {
+            None => {
+                let (account, channel) = current_channel(&aparte)?;
+                plugins::conversation::ConversationPlugin::room_config(aparte, &account, &channel);
+            },
+            Some(action @ "submit") | Some(action @ "cancel") => {
+                let (window, account, channel) = {
+                    let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+                    match ui.current_form() {
+                        Some(current) => current,
+                        None => return Err(format!("Current window is not a configuration form")),
+                    }
+                };
+
+                let form = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap().take_form(&window);
+                let form = match action {
+                    "submit" => form,
+                    _ => None,
+                };
+                plugins::conversation::ConversationPlugin::submit_room_config(Rc::clone(&aparte), &account, &channel, form);
+                Rc::clone(&aparte).event(Event::Close(window));
+                let window = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap().conversation_window(&account, &channel);
+                if let Some(window) = window {
+                    aparte.event(Event::Win(window));
+                }
+            },
+            Some(action) => return Err(format!("Unknown action {}", action)),
+        }
+
+        Ok(())
+    }
+}
+
+command_def!{
+    contact,
+    r#"/contact <action> <contact> [<argument>...]
+
+  action        One of add, remove, rename or group
+  contact       Contact JID
+  argument      Name for add and rename, groups for group
+
+Description:
+  Manage the roster: add a contact, remove it, change its name or set the
+  groups it belongs to.
+
+Examples:
+  /contact add contact@server.tld "Contact name"
+  /contact remove contact@server.tld
+  /contact rename contact@server.tld "New name"
+  /contact group contact@server.tld Friends Work"#,
+    action: {
+        completion: |_aparte, _command| {
+            vec!["add".to_string(), "remove".to_string(), "rename".to_string(), "group".to_string()]
+        }
+    },
+    contact: {
+        completion: |aparte, _command| {
+            let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+            contact.jids()
+        }
+    },
+    (optional) argument: {
+        completion: |aparte, command| {
+            match command.args.get(1).map(String::as_str) {
+                Some("group") => {
+                    let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+                    contact.groups()
+                },
+                _ => Vec::new(),
+            }
+        }
+    },
+    |aparte, command| {
+        let account = match current_account(&aparte) {
+            Some(account) => account,
+            None => return Err(format!("No connection found")),
+        };
+
+        let jid = match BareJid::from_str(&contact) {
+            Ok(jid) => jid,
+            Err(err) => return Err(format!("Invalid JID {}: {}", contact, err)),
+        };
+
+        let existing = {
+            let contacts = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+            contacts.contact(&account, &jid).map(plugins::contact::ContactPlugin::item)
+        };
+
+        let mut item = match (action.as_str(), existing) {
+            ("add", Some(_)) => return Err(format!("{} is already a contact", jid)),
+            ("add", None) => RosterItem {
+                jid: jid,
+                name: None,
+                subscription: RosterSubscription::None,
+                ask: RosterAsk::None,
+                groups: Vec::new(),
+            },
+            (_, Some(item)) => item,
+            (_, None) => return Err(format!("Unknown contact {}", jid)),
+        };
+
+        match action.as_str() {
+            "add" => item.name = argument,
+            "remove" => {
+                item.subscription = RosterSubscription::Remove;
+                item.name = None;
+                item.groups = Vec::new();
+            },
+            "rename" => match argument {
+                Some(name) => item.name = Some(name),
+                None => return Err(format!("Missing name argument")),
+            },
+            "group" => item.groups = command.args[3..].iter().map(|group| RosterGroup(group.clone())).collect(),
+            _ => return Err(format!("Unknown action {}", action)),
+        }
+
+        plugins::contact::ContactPlugin::set(aparte, &account, item);
+
+        Ok(())
+    }
+}
+
+command_def!{
+    subscription,
+    r#"/subscription <action> <jid>
+
+  action        One of approve, deny, request or cancel
+  jid           Contact JID
+
+Description:
+  Manage presence subscriptions. approve lets the contact see your presence,
+  before it even asked when no request is pending. deny refuses a pending
+  request or revokes an existing subscription. request asks to see the
+  presence of the contact and cancel stops seeing it.
+  Pending requests are listed in the subscriptions window.
+
+Examples:
+  /subscription approve contact@server.tld
+  /subscription deny contact@server.tld
+  /subscription request contact@server.tld
+  /subscription cancel contact@server.tld"#,
+    action: {
+        completion: |_aparte, _command| {
+            vec!["approve".to_string(), "deny".to_string(), "request".to_string(), "cancel".to_string()]
+        }
+    },
+    jid: {
+        completion: |aparte, _command| {
+            let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+            let mut jids = contact.subscription_requests.keys().map(|(_, jid)| jid.to_string()).collect::<Vec<_>>();
+            jids.extend(contact.jids());
+            jids.sort();
+            jids.dedup();
+            jids
+        }
+    },
+    |aparte, _command| {
+        let jid = match BareJid::from_str(&jid) {
+            Ok(jid) => jid,
+            Err(err) => return Err(format!("Invalid JID {}: {}", jid, err)),
+        };
+
+        let type_ = match action.as_str() {
+            "approve" => PresenceType::Subscribed,
+            "deny" => PresenceType::Unsubscribed,
+            "request" => PresenceType::Subscribe,
+            "cancel" => PresenceType::Unsubscribe,
+            _ => return Err(format!("Unknown action {}", action)),
+        };
+
+        // Pending requests are answered from the account they were received on, the current one
+        // being preferred when the contact asked several of our accounts
+        let current = current_account(&aparte);
+        let pending = match type_ {
+            PresenceType::Subscribed | PresenceType::Unsubscribed => {
+                let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+                let mut pending = contact.subscription_requests.values().filter(|request| request.jid == jid).cloned().collect::<Vec<_>>();
+                pending.sort_by_key(|request| Some(&request.account) != current.as_ref());
+                pending.into_iter().next()
+            },
+            _ => None,
+        };
+        let account = match pending.as_ref().map(|request| request.account.clone()).or(current) {
+            Some(account) => account,
+            None => return Err(format!("No connection found")),
+        };
+
+        let request = match type_ {
+            PresenceType::Subscribed | PresenceType::Unsubscribed => {
+                let mut contact = aparte.get_plugin_mut::<plugins::contact::ContactPlugin>().unwrap();
+                let request = contact.subscription_requests.remove(&(account.clone().into(), jid.clone()));
+                if type_ == PresenceType::Unsubscribed {
+                    contact.remove_pre_approval(&account, &jid);
+                }
+                request
+            },
+            _ => None,
+        };
+
+        if type_ == PresenceType::Subscribed && request.is_none() {
+            // Without server support, pre-approval is kept locally until the contact asks
+            let supported = match aparte.stream_features(&account) {
+                Some(features) => features.has_child("sub", "urn:xmpp:features:pre-approval"),
+                None => false,
+            };
+            if !supported {
+                let mut contact = aparte.get_plugin_mut::<plugins::contact::ContactPlugin>().unwrap();
+                contact.pre_approve(&account, &jid);
+                Rc::clone(&aparte).log(format!("{} will be approved when asking for your presence", jid));
+                return Ok(());
+            }
+        }
+
+        let presence = Presence::new(type_).with_to(Jid::Bare(jid));
+        aparte.send(&account, presence.into());
+
+        if let Some(request) = request {
+            aparte.event(Event::SubscriptionRequestRemoved(account, request));
+        }
+
+        Ok(())
+    }
+}
+
+command_def!{
+    status,
+    r#"/status <show> [<message>]
+
+  show          One of available, away, dnd, xa or chat
+  message       Optional status message
+
+Description:
+  Set your presence and status message on every connected account. It is
+  sent to your contacts and to the channels you joined, and restored on
+  next start.
+
+Examples:
+  /status dnd "On call"
+  /status away
+  /status available"#,
+    show: {
+        completion: |_aparte, _command| {
+            vec!["available".to_string(), "away".to_string(), "dnd".to_string(), "xa".to_string(), "chat".to_string()]
+        }
+    },
+    (optional) message,
+    |aparte, _command| {
+        let show = plugins::presence::PresencePlugin::parse_show(&show)?;
+        plugins::presence::PresencePlugin::set(aparte, show, message);
+
+        Ok(())
+    }
+}
+
+command_def!{
+    disco,
+    r#"/disco <jid> [<node>]
+
+  jid           Entity to discover
+  node          Optional node to query
+
+Description:
+  Show the identities, features and items of a server, component or contact.
+
+Example:
+  /disco server.tld
+  /disco conference.server.tld
+  /disco contact@server.tld/resource"#,
+    jid: {
+        completion: |aparte, _command| {
+            let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+            contact.jids()
+        }
+    },
+    (optional) node,
+    |aparte, _command| {
+        match current_account(&aparte) {
+            Some(account) => {
+                match Jid::from_str(&jid) {
+                    Ok(jid) => {
+                        let info = plugins::disco::Disco::query_info(Rc::clone(&aparte), &account, jid.clone(), node.clone());
+                        let items = plugins::disco::Disco::query_items(Rc::clone(&aparte), &account, jid.clone(), node).then(|result| Ok(result.ok()));
+
+                        let disco_aparte = Rc::clone(&aparte);
+                        let error_aparte = Rc::clone(&aparte);
+                        let error_jid = jid.clone();
+                        tokio::runtime::current_thread::spawn(info.join(items).map(move |(info, items)| {
+                            disco_aparte.event(Event::Disco { account: account, jid: jid, info: info, items: items });
+                        }).map_err(move |err| {
+                            error_aparte.log(format!("Cannot discover {}: {}", error_jid, err));
+                        }));
+
+                        Ok(())
+                    },
+                    Err(err) => {
+                        Err(format!("Invalid JID {}: {}", jid, err))
+                    }
+                }
+            },
+            None => {
+                Err(format!("No connection found"))
+            }
+        }
+    }
+}
+
 command_def!{
     quit,
     r#"/quit
@@ -315,6 +1306,8 @@ Description:
 Example:
   /quit"#,
     |aparte, _command| {
+        // Don't reconnect while quitting
+        aparte.cancel_connections();
         aparte.event(Event::Quit);
 
         Ok(())
@@ -374,11 +1367,23 @@ fn main() {
 
     info!("Starting aparté");
 
-    let mut aparte = Aparte::new(config);
+    let mut aparte = Aparte::new(config, aparte_data);
+
+    // Password commands may prompt on the terminal, e.g. through pinentry, run them while it is
+    // still usable
+    let passwords = aparte.config.accounts.iter()
+        .filter(|(_, account)| account.autoconnect)
+        .map(|(name, account)| (name.clone(), account.password()))
+        .collect::<HashMap<_, _>>();
+
     aparte.add_plugin(plugins::disco::Disco::new());
     aparte.add_plugin(plugins::carbons::CarbonsPlugin::new());
     aparte.add_plugin(plugins::contact::ContactPlugin::new());
     aparte.add_plugin(plugins::conversation::ConversationPlugin::new());
+    aparte.add_plugin(plugins::presence::PresencePlugin::new());
+    aparte.add_plugin(plugins::bookmarks::BookmarksPlugin::new());
+    aparte.add_plugin(plugins::mam::MamPlugin::new());
+    aparte.add_plugin(plugins::history::HistoryPlugin::new());
     aparte.add_plugin(plugins::ui::UIPlugin::new());
 
     aparte.add_command(help());
@@ -386,6 +1391,21 @@ fn main() {
     aparte.add_command(win());
     aparte.add_command(msg());
     aparte.add_command(join());
+    aparte.add_command(leave());
+    aparte.add_command(bookmark());
+    aparte.add_command(search());
+    aparte.add_command(close());
+    aparte.add_command(topic());
+    aparte.add_command(kick());
+    aparte.add_command(ban());
+    aparte.add_command(role());
+    aparte.add_command(affiliation());
+    aparte.add_command(voice());
+    aparte.add_command(roomconfig());
+    aparte.add_command(contact());
+    aparte.add_command(subscription());
+    aparte.add_command(status());
+    aparte.add_command(disco());
     aparte.add_command(quit());
 
     aparte.init().unwrap();
@@ -414,12 +1434,18 @@ fn main() {
 
     rt.spawn(signals);
 
+    let autoconnect_aparte = Rc::clone(&aparte);
+    rt.spawn(future::lazy(move || {
+        autoconnect(autoconnect_aparte, passwords);
+        Ok(())
+    }));
+
     rt.block_on(command_stream.for_each(move |command_or_message| {
         match command_or_message {
-            CommandOrMessage::Message(message) => {
-                Rc::clone(&aparte).event(Event::Message(message.clone()));
+            CommandOrMessage::Message(account, message) => {
+                Rc::clone(&aparte).event(Event::Message(Some(account.clone()), message.clone()));
                 if let Ok(xmpp_message) = Element::try_from(message) {
-                    aparte.send(xmpp_message);
+                    aparte.send(&account, xmpp_message);
                 }
             }
             CommandOrMessage::Command(command) => {
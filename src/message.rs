@@ -1,8 +1,10 @@
 use chrono::{Utc, DateTime};
+use std::cmp;
 use std::convert::TryFrom;
 use std::hash;
 use uuid::Uuid;
 use xmpp_parsers::{BareJid, Jid};
+use xmpp_parsers::delay::Delay;
 
 #[derive(Debug, Clone)]
 pub struct ChatMessage {
@@ -13,6 +15,9 @@ pub struct ChatMessage {
     pub to: BareJid,
     pub to_full: Jid,
     pub body: String,
+    pub acked: bool,
+    /// The server can't tell anymore whether it handled this outgoing message
+    pub failed: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -24,6 +29,9 @@ pub struct GroupchatMessage {
     pub to: BareJid,
     pub to_full: Jid,
     pub body: String,
+    pub acked: bool,
+    /// The server can't tell anymore whether it handled this outgoing message
+    pub failed: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -66,6 +74,8 @@ impl Message {
             to: to.clone(),
             to_full: to_full.clone(),
             body: body.to_string(),
+            acked: false,
+            failed: false,
         }))
     }
 
@@ -88,6 +98,8 @@ impl Message {
             to: to.clone(),
             to_full: to_full.clone(),
             body: body.to_string(),
+            acked: false,
+            failed: false,
         }))
     }
 
@@ -110,6 +122,8 @@ impl Message {
             to: to.clone(),
             to_full: to_full.clone(),
             body: body.to_string(),
+            acked: false,
+            failed: false,
         }))
     }
 
@@ -132,6 +146,8 @@ impl Message {
             to: to.clone(),
             to_full: to_full.clone(),
             body: body.to_string(),
+            acked: false,
+            failed: false,
         }))
     }
 
@@ -143,7 +159,57 @@ impl Message {
         })
     }
 
-    #[allow(dead_code)]
+    pub fn id(&self) -> &str {
+        match self {
+            Message::Outgoing(XmppMessage::Chat(ChatMessage { id, .. }))
+                | Message::Incoming(XmppMessage::Chat(ChatMessage { id, .. }))
+                | Message::Outgoing(XmppMessage::Groupchat(GroupchatMessage { id, .. }))
+                | Message::Incoming(XmppMessage::Groupchat(GroupchatMessage { id, .. }))
+                | Message::Log(LogMessage { id, .. }) => &id,
+        }
+    }
+
+    pub fn timestamp(&self) -> &DateTime<Utc> {
+        match self {
+            Message::Outgoing(XmppMessage::Chat(ChatMessage { timestamp, .. }))
+                | Message::Incoming(XmppMessage::Chat(ChatMessage { timestamp, .. }))
+                | Message::Outgoing(XmppMessage::Groupchat(GroupchatMessage { timestamp, .. }))
+                | Message::Incoming(XmppMessage::Groupchat(GroupchatMessage { timestamp, .. }))
+                | Message::Log(LogMessage { timestamp, .. }) => &timestamp,
+        }
+    }
+
+    /// Who wrote the message: a contact, a nick in a channel or ourselves
+    pub fn author(&self) -> String {
+        match self {
+            Message::Outgoing(_) => String::from("me"),
+            Message::Incoming(XmppMessage::Chat(message)) => message.from.to_string(),
+            Message::Incoming(XmppMessage::Groupchat(message)) => match &message.from_full {
+                Jid::Full(from) => from.resource.clone(),
+                Jid::Bare(from) => from.to_string(),
+            },
+            Message::Log(_) => String::new(),
+        }
+    }
+
+    /// Mark an outgoing message as acknowledged by the server
+    pub fn ack(&mut self) {
+        match self {
+            Message::Outgoing(XmppMessage::Chat(ChatMessage { acked, .. }))
+                | Message::Outgoing(XmppMessage::Groupchat(GroupchatMessage { acked, .. })) => *acked = true,
+            _ => {},
+        }
+    }
+
+    /// Mark an outgoing message as possibly lost
+    pub fn fail(&mut self) {
+        match self {
+            Message::Outgoing(XmppMessage::Chat(ChatMessage { failed, .. }))
+                | Message::Outgoing(XmppMessage::Groupchat(GroupchatMessage { failed, .. })) => *failed = true,
+            _ => {},
+        }
+    }
+
     pub fn body(&self) -> &str {
         match self {
             Message::Outgoing(XmppMessage::Chat(ChatMessage { body, .. }))
@@ -192,6 +258,39 @@ impl PartialEq for Message {
 impl std::cmp::Eq for Message {
 }
 
+/// Messages are ordered chronologically, the id only breaking ties. Copies of a message received
+/// with different timestamps are equal but not ordered together, buffers deduplicate them by id.
+impl PartialOrd for Message {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Message {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.timestamp().cmp(other.timestamp()).then_with(|| self.id().cmp(other.id()))
+    }
+}
+
+/// Time at which a delayed message was originally sent, see XEP-0203
+pub fn delay_timestamp(delay: &Delay) -> Option<DateTime<Utc>> {
+    match DateTime::parse_from_rfc3339(&delay.stamp.format("%+")) {
+        Ok(stamp) => Some(stamp.with_timezone(&Utc)),
+        Err(err) => {
+            warn!("Invalid delay stamp: {}", err);
+            None
+        },
+    }
+}
+
+/// Time at which a received stanza was originally sent, None if it wasn't delayed
+pub fn stanza_timestamp(message: &xmpp_parsers::message::Message) -> Option<DateTime<Utc>> {
+    message.payloads.iter()
+        .filter_map(|payload| Delay::try_from(payload.clone()).ok())
+        .next()
+        .and_then(|delay| delay_timestamp(&delay))
+}
+
 impl TryFrom<Message> for xmpp_parsers::Element {
     type Error = ();
 
@@ -220,3 +319,43 @@ impl TryFrom<Message> for xmpp_parsers::Element {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use xmpp_parsers::Element;
+
+    fn stanza(xml: &str) -> xmpp_parsers::message::Message {
+        xmpp_parsers::message::Message::try_from(Element::from_str(xml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_order_is_chronological() {
+        let from = Jid::from_str("contact@server.tld/phone").unwrap();
+        let to = Jid::from_str("me@server.tld/aparte").unwrap();
+        let early = DateTime::parse_from_rfc3339("2020-01-01T10:00:00Z").unwrap().with_timezone(&Utc);
+        let late = DateTime::parse_from_rfc3339("2020-01-01T11:00:00Z").unwrap().with_timezone(&Utc);
+
+        let first = Message::incoming_chat("b", early, &from, &to, "Hi");
+        let second = Message::incoming_chat("a", late, &from, &to, "Hello");
+        let tie = Message::incoming_chat("c", early, &from, &to, "Hey");
+        let copy = Message::incoming_chat("b", late, &from, &to, "Hi");
+
+        assert!(first < tie);
+        assert!(tie < second);
+        assert!(first < second);
+        assert_eq!(first, copy);
+        assert!(second < copy);
+        assert_eq!(first.cmp(&first.clone()), cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn test_delayed_stanza_timestamp() {
+        let delayed = stanza("<message xmlns='jabber:client' type='groupchat' from='room@conference.server.tld/nick' to='me@server.tld/aparte'><body>Hi</body><delay xmlns='urn:xmpp:delay' from='room@conference.server.tld' stamp='2020-01-01T10:00:00+01:00'/></message>");
+        assert_eq!(stanza_timestamp(&delayed).unwrap().to_rfc3339(), "2020-01-01T09:00:00+00:00");
+
+        let live = stanza("<message xmlns='jabber:client' type='chat' from='contact@server.tld/phone' to='me@server.tld/aparte'><body>Hi</body></message>");
+        assert_eq!(stanza_timestamp(&live), None);
+    }
+}
@@ -0,0 +1,211 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+use xmpp_parsers::{Element, Error, Jid, ns};
+use xmpp_parsers::data_forms::DataForm;
+use xmpp_parsers::iq::{IqGetPayload, IqResultPayload, IqSetPayload};
+use xmpp_parsers::muc::user::{Affiliation, Role};
+
+pub const NS_MUC_ADMIN: &str = "http://jabber.org/protocol/muc#admin";
+pub const NS_MUC_OWNER: &str = "http://jabber.org/protocol/muc#owner";
+
+/// Role or affiliation change of an occupant, see XEP-0045 §8 and §9
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdminItem {
+    pub nick: Option<String>,
+    pub jid: Option<Jid>,
+    pub role: Option<Role>,
+    pub affiliation: Option<Affiliation>,
+    pub reason: Option<String>,
+}
+
+impl AdminItem {
+    pub fn role(nick: &str, role: Role, reason: Option<String>) -> Self {
+        Self {
+            nick: Some(nick.to_string()),
+            jid: None,
+            role: Some(role),
+            affiliation: None,
+            reason: reason,
+        }
+    }
+
+    pub fn affiliation(jid: Jid, affiliation: Affiliation, reason: Option<String>) -> Self {
+        Self {
+            nick: None,
+            jid: Some(jid),
+            role: None,
+            affiliation: Some(affiliation),
+            reason: reason,
+        }
+    }
+}
+
+/// muc#admin query, sent by moderators and admins of a room
+#[derive(Debug, Clone, PartialEq)]
+pub struct Admin {
+    pub items: Vec<AdminItem>,
+}
+
+impl IqGetPayload for Admin {}
+impl IqSetPayload for Admin {}
+impl IqResultPayload for Admin {}
+
+// Default values are omitted when serializing the parsers' attributes, but kicking requires an
+// explicit role='none'
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::Moderator => "moderator",
+        Role::Participant => "participant",
+        Role::Visitor => "visitor",
+        Role::None => "none",
+    }
+}
+
+fn affiliation_name(affiliation: Affiliation) -> &'static str {
+    match affiliation {
+        Affiliation::Owner => "owner",
+        Affiliation::Admin => "admin",
+        Affiliation::Member => "member",
+        Affiliation::Outcast => "outcast",
+        Affiliation::None => "none",
+    }
+}
+
+impl From<AdminItem> for Element {
+    fn from(item: AdminItem) -> Element {
+        let mut builder = Element::builder("item").ns(NS_MUC_ADMIN)
+            .attr("nick", item.nick)
+            .attr("jid", item.jid.map(|jid| jid.to_string()))
+            .attr("role", item.role.map(role_name))
+            .attr("affiliation", item.affiliation.map(affiliation_name));
+
+        if let Some(reason) = item.reason {
+            builder = builder.append(Element::builder("reason").ns(NS_MUC_ADMIN).append(reason).build());
+        }
+
+        builder.build()
+    }
+}
+
+impl From<Admin> for Element {
+    fn from(admin: Admin) -> Element {
+        let mut query = Element::builder("query").ns(NS_MUC_ADMIN).build();
+        for item in admin.items {
+            query.append_child(item.into());
+        }
+
+        query
+    }
+}
+
+impl TryFrom<Element> for AdminItem {
+    type Error = Error;
+
+    fn try_from(element: Element) -> Result<Self, Error> {
+        if !element.is("item", NS_MUC_ADMIN) {
+            return Err(Error::ParseError("This is not a muc#admin item element."));
+        }
+
+        Ok(Self {
+            nick: element.attr("nick").map(str::to_string),
+            jid: element.attr("jid").map(Jid::from_str).transpose()?,
+            role: element.attr("role").map(Role::from_str).transpose()?,
+            affiliation: element.attr("affiliation").map(Affiliation::from_str).transpose()?,
+            reason: element.get_child("reason", NS_MUC_ADMIN).map(Element::text),
+        })
+    }
+}
+
+impl TryFrom<Element> for Admin {
+    type Error = Error;
+
+    fn try_from(element: Element) -> Result<Self, Error> {
+        if !element.is("query", NS_MUC_ADMIN) {
+            return Err(Error::ParseError("This is not a muc#admin query element."));
+        }
+
+        let mut items = Vec::new();
+        for child in element.children() {
+            items.push(AdminItem::try_from(child.clone())?);
+        }
+
+        Ok(Self { items: items })
+    }
+}
+
+/// muc#owner query, empty to request the configuration form, with the filled form to submit it
+#[derive(Debug, Clone)]
+pub struct Owner {
+    pub form: Option<DataForm>,
+}
+
+impl IqGetPayload for Owner {}
+impl IqSetPayload for Owner {}
+impl IqResultPayload for Owner {}
+
+impl From<Owner> for Element {
+    fn from(owner: Owner) -> Element {
+        let mut query = Element::builder("query").ns(NS_MUC_OWNER).build();
+        if let Some(form) = owner.form {
+            query.append_child(form.into());
+        }
+
+        query
+    }
+}
+
+impl TryFrom<Element> for Owner {
+    type Error = Error;
+
+    fn try_from(element: Element) -> Result<Self, Error> {
+        if !element.is("query", NS_MUC_OWNER) {
+            return Err(Error::ParseError("This is not a muc#owner query element."));
+        }
+
+        let form = match element.get_child("x", ns::DATA_FORMS) {
+            Some(form) => {
+                // Fixed fields are only descriptive text and may have no var, which the parser refuses
+                let mut form = form.clone();
+                for field in form.children_mut().filter(|field| field.attr("var").is_none()) {
+                    field.set_attr("var", "");
+                }
+                Some(DataForm::try_from(form)?)
+            },
+            None => None,
+        };
+
+        Ok(Self { form: form })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_round_trip() {
+        let admin = Admin {
+            items: vec![
+                AdminItem::role("troll", Role::None, Some("Spam".to_string())),
+                AdminItem::affiliation(Jid::from_str("troll@server.tld").unwrap(), Affiliation::Outcast, None),
+            ],
+        };
+
+        let element: Element = admin.clone().into();
+        let item = element.children().next().unwrap();
+        assert_eq!(item.attr("nick"), Some("troll"));
+        assert_eq!(item.attr("role"), Some("none"));
+        assert_eq!(item.get_child("reason", NS_MUC_ADMIN).unwrap().text(), "Spam");
+
+        assert_eq!(Admin::try_from(element).unwrap(), admin);
+    }
+
+    #[test]
+    fn test_owner_form_with_anonymous_fixed_field() {
+        let element = Element::from_str("<query xmlns='http://jabber.org/protocol/muc#owner'><x xmlns='jabber:x:data' type='form'><field type='fixed'><value>Room settings</value></field><field var='muc#roomconfig_persistentroom' type='boolean'><value>0</value></field></x></query>").unwrap();
+
+        let form = Owner::try_from(element).unwrap().form.unwrap();
+        assert_eq!(form.fields.len(), 2);
+        assert_eq!(form.fields[1].var, "muc#roomconfig_persistentroom");
+    }
+}
@@ -0,0 +1,503 @@
+use futures::Future;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::{bookmarks, bookmarks2, BareJid, Element, FullJid, Jid, ns};
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+use xmpp_parsers::iq::Iq;
+use xmpp_parsers::pubsub::{self, ItemId, NodeName, PubSub, PubSubEvent};
+use xmpp_parsers::pubsub::pubsub::{Items, Notify, Publish, PublishOptions, Retract};
+use xmpp_parsers::stanza_error::DefinedCondition;
+
+use crate::core::{Plugin, Aparte, Event, IqError};
+use crate::plugins::conversation::ConversationPlugin;
+use crate::plugins::disco;
+
+const NS_BOOKMARKS2_NOTIFY: &str = "urn:xmpp:bookmarks:0+notify";
+const NS_BOOKMARKS_NOTIFY: &str = "storage:bookmarks+notify";
+const NS_PUBLISH_OPTIONS: &str = "http://jabber.org/protocol/pubsub#publish-options";
+/// Item holding the whole storage element of XEP-0048 bookmarks
+const LEGACY_ITEM: &str = "current";
+
+/// Where the bookmarks of an account are stored
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    /// XEP-0402, one PEP item per bookmark
+    Bookmarks2,
+    /// XEP-0048 in PEP, a single item listing every bookmark
+    Legacy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bookmark {
+    pub jid: BareJid,
+    pub name: Option<String>,
+    pub nick: Option<String>,
+    pub password: Option<String>,
+    pub autojoin: bool,
+}
+
+impl Bookmark {
+    fn from_conference(jid: BareJid, conference: bookmarks2::Conference) -> Self {
+        Self {
+            jid: jid,
+            name: conference.name,
+            nick: conference.nick,
+            password: conference.password,
+            autojoin: conference.autojoin == bookmarks2::Autojoin::True,
+        }
+    }
+}
+
+impl From<bookmarks::Conference> for Bookmark {
+    fn from(conference: bookmarks::Conference) -> Self {
+        Self {
+            jid: conference.jid,
+            name: Some(conference.name).filter(|name| !name.is_empty()),
+            nick: conference.nick,
+            password: conference.password,
+            autojoin: conference.autojoin == bookmarks::Autojoin::True,
+        }
+    }
+}
+
+impl From<Bookmark> for bookmarks2::Conference {
+    fn from(bookmark: Bookmark) -> Self {
+        Self {
+            autojoin: match bookmark.autojoin {
+                true => bookmarks2::Autojoin::True,
+                false => bookmarks2::Autojoin::False,
+            },
+            name: bookmark.name,
+            nick: bookmark.nick,
+            password: bookmark.password,
+        }
+    }
+}
+
+impl From<Bookmark> for bookmarks::Conference {
+    fn from(bookmark: Bookmark) -> Self {
+        // A name is required by XEP-0048
+        let name = match bookmark.name {
+            Some(name) => name,
+            None => bookmark.jid.to_string(),
+        };
+
+        Self {
+            autojoin: match bookmark.autojoin {
+                true => bookmarks::Autojoin::True,
+                false => bookmarks::Autojoin::False,
+            },
+            name: name,
+            jid: bookmark.jid,
+            nick: bookmark.nick,
+            password: bookmark.password,
+        }
+    }
+}
+
+impl fmt::Display for Bookmark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.jid)?;
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        if let Some(nick) = &self.nick {
+            write!(f, " as {}", nick)?;
+        }
+        if self.autojoin {
+            write!(f, " [autojoin]")?;
+        }
+        Ok(())
+    }
+}
+
+struct AccountBookmarks {
+    protocol: Protocol,
+    bookmarks: Vec<Bookmark>,
+    /// Web pages bookmarked by other clients in XEP-0048 storage, kept when we republish it
+    urls: Vec<bookmarks::Url>,
+}
+
+pub struct BookmarksPlugin {
+    accounts: HashMap<BareJid, AccountBookmarks>,
+}
+
+impl BookmarksPlugin {
+    fn request(node: &str) -> Iq {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        Iq::from_get(id, PubSub::Items(Items::new(node)))
+    }
+
+    /// Bookmarks and URLs found in the items of the node used by the given protocol
+    fn parse(protocol: Protocol, items: Vec<pubsub::Item>) -> (Vec<Bookmark>, Vec<bookmarks::Url>) {
+        let mut bookmarks = Vec::new();
+        let mut urls = Vec::new();
+
+        for item in items {
+            let payload = match item.payload {
+                Some(payload) => payload,
+                None => continue,
+            };
+
+            match protocol {
+                Protocol::Bookmarks2 => {
+                    let jid = item.id.as_ref().map(|id| BareJid::from_str(&id.0));
+                    match (jid, bookmarks2::Conference::try_from(payload)) {
+                        (Some(Ok(jid)), Ok(conference)) => bookmarks.push(Bookmark::from_conference(jid, conference)),
+                        _ => warn!("Invalid bookmark {:?}", item.id),
+                    }
+                },
+                Protocol::Legacy => match bookmarks::Storage::try_from(payload) {
+                    Ok(storage) => {
+                        bookmarks.extend(storage.conferences.into_iter().map(Bookmark::from));
+                        urls.extend(storage.urls);
+                    },
+                    Err(err) => warn!("Invalid bookmarks storage: {}", err),
+                },
+            }
+        }
+
+        (bookmarks, urls)
+    }
+
+    fn parse_result(protocol: Protocol, payload: Option<Element>) -> (Vec<Bookmark>, Vec<bookmarks::Url>) {
+        match payload.map(PubSub::try_from) {
+            Some(Ok(PubSub::Items(items))) => Self::parse(protocol, items.items.into_iter().map(|item| item.0).collect()),
+            Some(Ok(_)) => (Vec::new(), Vec::new()),
+            Some(Err(err)) => {
+                warn!("Invalid bookmarks: {}", err);
+                (Vec::new(), Vec::new())
+            },
+            None => (Vec::new(), Vec::new()),
+        }
+    }
+
+    /// Keep bookmarks private and let the node hold as many of them as needed
+    fn publish_options() -> PublishOptions {
+        let option = |var: &str, value: &str| Field {
+            var: var.to_string(),
+            type_: FieldType::TextSingle,
+            label: None,
+            required: false,
+            options: Vec::new(),
+            values: vec![value.to_string()],
+            media: Vec::new(),
+        };
+
+        PublishOptions {
+            form: Some(DataForm {
+                type_: DataFormType::Submit,
+                form_type: Some(NS_PUBLISH_OPTIONS.to_string()),
+                title: None,
+                instructions: None,
+                fields: vec![
+                    option("pubsub#persist_items", "true"),
+                    option("pubsub#max_items", "max"),
+                    option("pubsub#send_last_published_item", "never"),
+                    option("pubsub#access_model", "whitelist"),
+                ],
+            }),
+        }
+    }
+
+    fn publish(node: &str, item: &str, payload: Element) -> Iq {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        Iq::from_set(id, PubSub::Publish {
+            publish: Publish {
+                node: NodeName(node.to_string()),
+                items: vec![pubsub::pubsub::Item(pubsub::Item {
+                    id: Some(ItemId(item.to_string())),
+                    publisher: None,
+                    payload: Some(payload),
+                })],
+            },
+            publish_options: Some(Self::publish_options()),
+        })
+    }
+
+    fn retract(node: &str, item: &str) -> Iq {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        Iq::from_set(id, PubSub::Retract(Retract {
+            node: NodeName(node.to_string()),
+            notify: Notify::True,
+            items: vec![pubsub::pubsub::Item(pubsub::Item {
+                id: Some(ItemId(item.to_string())),
+                publisher: None,
+                payload: None,
+            })],
+        }))
+    }
+
+    /// Room JIDs bookmarked on any account
+    pub fn channels(&self) -> Vec<String> {
+        let mut channels = self.accounts.values()
+            .flat_map(|account| account.bookmarks.iter().map(|bookmark| bookmark.jid.to_string()))
+            .collect::<Vec<_>>();
+        channels.sort();
+        channels.dedup();
+        channels
+    }
+
+    pub fn bookmarks(&self, account: &FullJid) -> Vec<Bookmark> {
+        let bare: BareJid = account.clone().into();
+        match self.accounts.get(&bare) {
+            Some(account) => account.bookmarks.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn bookmark(&self, account: &FullJid, jid: &BareJid) -> Option<Bookmark> {
+        self.bookmarks(account).into_iter().find(|bookmark| &bookmark.jid == jid)
+    }
+
+    /// Join the rooms flagged autojoin we are not already in
+    fn autojoin(aparte: Rc<Aparte>, account: &FullJid, bookmarks: &[Bookmark]) {
+        for bookmark in bookmarks.iter().filter(|bookmark| bookmark.autojoin) {
+            if aparte.get_plugin::<ConversationPlugin>().unwrap().channel(account, &bookmark.jid).is_some() {
+                continue;
+            }
+
+            let nick = match &bookmark.nick {
+                Some(nick) => nick.clone(),
+                None => ConversationPlugin::default_nick(&aparte, account),
+            };
+            let channel = bookmark.jid.clone().with_resource(nick);
+            ConversationPlugin::join(Rc::clone(&aparte), account, channel, bookmark.password.clone());
+        }
+    }
+
+    fn handle_bookmarks(aparte: Rc<Aparte>, account: &FullJid, protocol: Protocol, (bookmarks, urls): (Vec<Bookmark>, Vec<bookmarks::Url>)) {
+        {
+            let mut plugin = aparte.get_plugin_mut::<BookmarksPlugin>().unwrap();
+            plugin.accounts.insert(account.clone().into(), AccountBookmarks {
+                protocol: protocol,
+                bookmarks: bookmarks.clone(),
+                urls: urls,
+            });
+        }
+
+        Self::autojoin(aparte, account, &bookmarks);
+    }
+
+    /// Fetch XEP-0402 bookmarks, or XEP-0048 ones stored in PEP when there are none
+    fn fetch(aparte: Rc<Aparte>, account: &FullJid) {
+        let legacy_aparte = Rc::clone(&aparte);
+        let legacy_account = account.clone();
+        let handle_aparte = Rc::clone(&aparte);
+        let handle_account = account.clone();
+        let error_aparte = Rc::clone(&aparte);
+
+        let bookmarks2 = Rc::clone(&aparte).iq(account, Self::request(ns::BOOKMARKS2)).map(|payload| {
+            (Protocol::Bookmarks2, Self::parse_result(Protocol::Bookmarks2, payload))
+        });
+        let future = bookmarks2.or_else(move |err| {
+            debug!("Cannot get bookmarks2, trying legacy bookmarks: {}", err);
+            legacy_aparte.iq(&legacy_account, Self::request(ns::BOOKMARKS)).then(|result| match result {
+                Ok(payload) => Ok((Protocol::Legacy, Self::parse_result(Protocol::Legacy, payload))),
+                // Nothing bookmarked yet, new bookmarks will be stored the XEP-0402 way
+                Err(IqError::Stanza(ref error)) if error.defined_condition == DefinedCondition::ItemNotFound => Ok((Protocol::Bookmarks2, (Vec::new(), Vec::new()))),
+                Err(err) => Err(err),
+            })
+        });
+
+        tokio::runtime::current_thread::spawn(future.map(move |(protocol, bookmarks)| {
+            Self::handle_bookmarks(handle_aparte, &handle_account, protocol, bookmarks);
+        }).map_err(move |err| {
+            error_aparte.log(format!("Cannot get bookmarks: {}", err));
+        }));
+    }
+
+    /// Add, replace or remove (when `bookmark` is None) the bookmark of a room
+    pub fn update(aparte: Rc<Aparte>, account: &FullJid, jid: &BareJid, bookmark: Option<Bookmark>) -> Result<(), String> {
+        let bare: BareJid = account.clone().into();
+        let (protocol, mut bookmarks, urls) = match aparte.get_plugin::<BookmarksPlugin>().unwrap().accounts.get(&bare) {
+            Some(account) => (account.protocol, account.bookmarks.clone(), account.urls.clone()),
+            None => return Err(format!("Bookmarks of {} are not loaded yet", bare)),
+        };
+
+        bookmarks.retain(|existing| &existing.jid != jid);
+        if let Some(bookmark) = bookmark.clone() {
+            bookmarks.push(bookmark);
+        }
+
+        let iq = match (protocol, bookmark.clone()) {
+            (Protocol::Bookmarks2, Some(bookmark)) => {
+                let conference: bookmarks2::Conference = bookmark.into();
+                Self::publish(ns::BOOKMARKS2, &jid.to_string(), conference.into())
+            },
+            (Protocol::Bookmarks2, None) => Self::retract(ns::BOOKMARKS2, &jid.to_string()),
+            (Protocol::Legacy, _) => {
+                let storage = bookmarks::Storage {
+                    conferences: bookmarks.iter().cloned().map(bookmarks::Conference::from).collect(),
+                    urls: urls.clone(),
+                };
+                Self::publish(ns::BOOKMARKS, LEGACY_ITEM, storage.into())
+            },
+        };
+
+        let done_aparte = Rc::clone(&aparte);
+        let error_aparte = Rc::clone(&aparte);
+        let jid = jid.clone();
+        let error_jid = jid.clone();
+        tokio::runtime::current_thread::spawn(Rc::clone(&aparte).iq(account, iq).map(move |_| {
+            {
+                let mut plugin = done_aparte.get_plugin_mut::<BookmarksPlugin>().unwrap();
+                plugin.accounts.insert(bare, AccountBookmarks {
+                    protocol: protocol,
+                    bookmarks: bookmarks,
+                    urls: urls,
+                });
+            }
+            match bookmark {
+                Some(_) => done_aparte.log(format!("Bookmarked {}", jid)),
+                None => done_aparte.log(format!("Removed bookmark of {}", jid)),
+            }
+        }).map_err(move |err| {
+            error_aparte.log(format!("Cannot update bookmark of {}: {}", error_jid, err));
+        }));
+
+        Ok(())
+    }
+
+    /// Apply a PEP notification about our bookmarks, published by us or by another client, returning
+    /// the ones newly flagged autojoin
+    fn handle_event(&mut self, account: &FullJid, event: &PubSubEvent) -> Vec<Bookmark> {
+        let bare: BareJid = account.clone().into();
+        let account = match self.accounts.get_mut(&bare) {
+            Some(account) => account,
+            None => return Vec::new(),
+        };
+        let previous = account.bookmarks.clone();
+
+        let changed = match event {
+            PubSubEvent::PublishedItems { node, items } if node.0 == ns::BOOKMARKS2 => {
+                let (changed, _) = Self::parse(Protocol::Bookmarks2, items.iter().map(|item| item.0.clone()).collect());
+                account.bookmarks.retain(|existing| !changed.iter().any(|bookmark| bookmark.jid == existing.jid));
+                account.bookmarks.extend(changed.iter().cloned());
+                changed
+            },
+            PubSubEvent::RetractedItems { node, items } if node.0 == ns::BOOKMARKS2 => {
+                account.bookmarks.retain(|existing| !items.iter().any(|id| id.0 == existing.jid.to_string()));
+                Vec::new()
+            },
+            PubSubEvent::PublishedItems { node, items } if node.0 == ns::BOOKMARKS => {
+                // The whole list is published again whenever any of its bookmarks changes
+                let (bookmarks, urls) = Self::parse(Protocol::Legacy, items.iter().map(|item| item.0.clone()).collect());
+                account.bookmarks = bookmarks.clone();
+                account.urls = urls;
+                bookmarks
+            },
+            PubSubEvent::Purge { node } | PubSubEvent::Delete { node, .. } if node.0 == ns::BOOKMARKS2 || node.0 == ns::BOOKMARKS => {
+                account.bookmarks.clear();
+                account.urls.clear();
+                Vec::new()
+            },
+            _ => Vec::new(),
+        };
+
+        // Rooms already flagged autojoin may have been left on purpose, don't join them again
+        changed.into_iter()
+            .filter(|bookmark| bookmark.autojoin)
+            .filter(|bookmark| !previous.iter().any(|old| old.jid == bookmark.jid && old.autojoin))
+            .collect()
+    }
+}
+
+impl Plugin for BookmarksPlugin {
+    fn new() -> BookmarksPlugin {
+        Self {
+            accounts: HashMap::new(),
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        let mut disco = aparte.get_plugin_mut::<disco::Disco>().unwrap();
+        disco.add_feature(NS_BOOKMARKS2_NOTIFY)?;
+        disco.add_feature(NS_BOOKMARKS_NOTIFY)
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(account) => Self::fetch(aparte, account),
+            Event::PubSub { account, from, event } => {
+                // Only our own server may tell us about our bookmarks
+                let bare: BareJid = account.clone().into();
+                match from {
+                    Jid::Bare(from) if from == &bare => {},
+                    _ => return,
+                }
+
+                let autojoin = self.handle_event(account, event);
+                Self::autojoin(aparte, account, &autojoin);
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for BookmarksPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0402: PEP Native Bookmarks")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account() -> FullJid {
+        FullJid::from_str("me@server.tld/aparte").unwrap()
+    }
+
+    #[test]
+    fn test_parse_bookmarks2_and_legacy() {
+        let element = Element::from_str("<pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:bookmarks:0'><item id='room@conference.server.tld'><conference xmlns='urn:xmpp:bookmarks:0' autojoin='true' name='Room'><nick>me</nick></conference></item></items></pubsub>").unwrap();
+        let (bookmarks, urls) = BookmarksPlugin::parse_result(Protocol::Bookmarks2, Some(element));
+        assert!(urls.is_empty());
+        assert_eq!(bookmarks, vec![Bookmark {
+            jid: BareJid::from_str("room@conference.server.tld").unwrap(),
+            name: Some("Room".to_string()),
+            nick: Some("me".to_string()),
+            password: None,
+            autojoin: true,
+        }]);
+
+        let element = Element::from_str("<pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='storage:bookmarks'><item id='current'><storage xmlns='storage:bookmarks'><conference jid='room@conference.server.tld' name='Room' autojoin='true'><nick>me</nick></conference><url name='Project' url='https://example.org/'/></storage></item></items></pubsub>").unwrap();
+        let (legacy, urls) = BookmarksPlugin::parse_result(Protocol::Legacy, Some(element));
+        assert_eq!(legacy, bookmarks);
+        assert_eq!(urls.len(), 1);
+        assert_eq!(urls[0].url, "https://example.org/");
+    }
+
+    #[test]
+    fn test_pep_notifications() {
+        let mut plugin = BookmarksPlugin::new();
+        plugin.accounts.insert(account().into(), AccountBookmarks { protocol: Protocol::Bookmarks2, bookmarks: Vec::new(), urls: Vec::new() });
+
+        let event = PubSubEvent::try_from(Element::from_str("<event xmlns='http://jabber.org/protocol/pubsub#event'><items node='urn:xmpp:bookmarks:0'><item id='room@conference.server.tld'><conference xmlns='urn:xmpp:bookmarks:0' autojoin='true'/></item></items></event>").unwrap()).unwrap();
+        assert_eq!(plugin.handle_event(&account(), &event).len(), 1);
+        assert_eq!(plugin.channels(), vec!["room@conference.server.tld".to_string()]);
+        assert!(plugin.handle_event(&account(), &event).is_empty());
+
+        let event = PubSubEvent::try_from(Element::from_str("<event xmlns='http://jabber.org/protocol/pubsub#event'><items node='urn:xmpp:bookmarks:0'><retract id='room@conference.server.tld'/></items></event>").unwrap()).unwrap();
+        plugin.handle_event(&account(), &event);
+        assert!(plugin.channels().is_empty());
+    }
+    #[test]
+    fn test_legacy_notification_only_joins_new_rooms() {
+        let mut plugin = BookmarksPlugin::new();
+        plugin.accounts.insert(account().into(), AccountBookmarks { protocol: Protocol::Legacy, bookmarks: Vec::new(), urls: Vec::new() });
+
+        let event = PubSubEvent::try_from(Element::from_str("<event xmlns='http://jabber.org/protocol/pubsub#event'><items node='storage:bookmarks'><item id='current'><storage xmlns='storage:bookmarks'><conference jid='left@conference.server.tld' name='Left' autojoin='true'/></storage></item></items></event>").unwrap()).unwrap();
+        assert_eq!(plugin.handle_event(&account(), &event).len(), 1);
+
+        let event = PubSubEvent::try_from(Element::from_str("<event xmlns='http://jabber.org/protocol/pubsub#event'><items node='storage:bookmarks'><item id='current'><storage xmlns='storage:bookmarks'><conference jid='left@conference.server.tld' name='Left' autojoin='true'/><conference jid='new@conference.server.tld' name='New' autojoin='true'/><url name='Project' url='https://example.org/'/></storage></item></items></event>").unwrap()).unwrap();
+        let autojoin = plugin.handle_event(&account(), &event);
+        assert_eq!(autojoin.iter().map(|bookmark| bookmark.jid.to_string()).collect::<Vec<_>>(), vec!["new@conference.server.tld".to_string()]);
+        assert_eq!(plugin.accounts[&account().into()].urls.len(), 1);
+    }
+}
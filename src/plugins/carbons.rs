@@ -1,21 +1,77 @@
+use chrono::Utc;
+use futures::{future, Future};
+use futures::future::Either;
+use std::convert::TryFrom;
 use std::fmt;
 use std::rc::Rc;
 use uuid::Uuid;
-use xmpp_parsers::Element;
 use xmpp_parsers::carbons;
+use xmpp_parsers::forwarding::Forwarded;
 use xmpp_parsers::iq::Iq;
+use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType};
+use xmpp_parsers::{BareJid, FullJid, Jid, ns};
 
 use crate::core::{Plugin, Aparte, Event};
+use crate::message::{self, Message};
 use crate::plugins::disco;
 
 pub struct CarbonsPlugin {
 }
 
 impl CarbonsPlugin {
-    fn enable(&self) -> Element {
+    fn enable(&self) -> Iq {
         let id = Uuid::new_v4().to_hyphenated().to_string();
-        let iq = Iq::from_set(id, carbons::Enable);
-        iq.into()
+        Iq::from_set(id, carbons::Enable)
+    }
+
+    /// Copy of a message sent or received by another of our resources, None if the stanza isn't a
+    /// carbon or wasn't sent by our own server
+    pub fn carbon_message(account: &FullJid, stanza: &XmppParsersMessage) -> Option<Message> {
+        let (forwarded, sent) = stanza.payloads.iter().filter_map(|payload| {
+            match carbons::Received::try_from(payload.clone()) {
+                Ok(received) => Some((received.forwarded, false)),
+                Err(_) => carbons::Sent::try_from(payload.clone()).ok().map(|sent| (sent.forwarded, true)),
+            }
+        }).next()?;
+
+        // Anyone could forward us a fake copy of a message, including our other resources, only trust
+        // those coming from our bare account
+        let bare: BareJid = account.clone().into();
+        match &stanza.from {
+            Some(Jid::Bare(from)) if from == &bare => {},
+            _ => {
+                warn!("Ignoring carbon from {:?}", stanza.from);
+                return None;
+            },
+        }
+
+        Self::forwarded_message(account, &forwarded, sent)
+    }
+
+    fn forwarded_message(account: &FullJid, forwarded: &Forwarded, sent: bool) -> Option<Message> {
+        let original = forwarded.stanza.as_ref()?;
+        match original.type_ {
+            XmppParsersMessageType::Chat | XmppParsersMessageType::Normal => {},
+            _ => return None,
+        }
+
+        let body = original.bodies.get("")?;
+        let id = original.id.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
+        let timestamp = message::stanza_timestamp(original)
+            .or_else(|| forwarded.delay.as_ref().and_then(message::delay_timestamp))
+            .unwrap_or_else(Utc::now);
+        let from = original.from.clone().unwrap_or_else(|| Jid::Bare(account.clone().into()));
+        let to = original.to.clone().unwrap_or_else(|| Jid::Bare(account.clone().into()));
+
+        match sent {
+            true => {
+                // Sent by another of our clients, the server already got it
+                let mut message = Message::outgoing_chat(id, timestamp, &from, &to, &body.0);
+                message.ack();
+                Some(message)
+            },
+            false => Some(Message::incoming_chat(id, timestamp, &from, &to, &body.0)),
+        }
     }
 }
 
@@ -26,12 +82,31 @@ impl Plugin for CarbonsPlugin {
 
     fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
         let mut disco = aparte.get_plugin_mut::<disco::Disco>().unwrap();
-        disco.add_feature("urn:xmpp:carbons:2")
+        disco.add_feature(ns::CARBONS)
     }
 
     fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
         match event {
-            Event::Connected(_jid) => aparte.send(self.enable()),
+            Event::Connected(account) => {
+                let enable_aparte = Rc::clone(&aparte);
+                let error_aparte = Rc::clone(&aparte);
+                let account = account.clone();
+                let server = Jid::Bare(BareJid::domain(&account.domain));
+                let enable = self.enable();
+                tokio::runtime::current_thread::spawn(disco::Disco::supports(Rc::clone(&aparte), &account, server, ns::CARBONS).and_then(move |supported| {
+                    match supported {
+                        true => Either::A(enable_aparte.iq(&account, enable).map(|_| {
+                            debug!("Carbons enabled");
+                        })),
+                        false => {
+                            debug!("Server doesn't support carbons");
+                            Either::B(future::ok(()))
+                        },
+                    }
+                }).map_err(move |err| {
+                    error_aparte.log(format!("Cannot enable carbons: {}", err));
+                }));
+            },
             _ => {},
         }
     }
@@ -42,3 +117,45 @@ impl fmt::Display for CarbonsPlugin {
         write!(f, "XEP-0280: Message Carbons")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use xmpp_parsers::Element;
+
+    fn stanza(xml: &str) -> XmppParsersMessage {
+        XmppParsersMessage::try_from(Element::from_str(xml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_carbon_direction() {
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+
+        let sent = CarbonsPlugin::carbon_message(&account, &stanza("<message xmlns='jabber:client' from='me@server.tld' to='me@server.tld/aparte'><sent xmlns='urn:xmpp:carbons:2'><forwarded xmlns='urn:xmpp:forward:0'><message xmlns='jabber:client' type='chat' id='sent' from='me@server.tld/phone' to='contact@server.tld'><body>Hello</body></message></forwarded></sent></message>")).unwrap();
+        match &sent {
+            Message::Outgoing(message::XmppMessage::Chat(message)) => {
+                assert_eq!(message.to, BareJid::from_str("contact@server.tld").unwrap());
+                assert!(message.acked);
+            },
+            _ => panic!("Expected outgoing chat message"),
+        }
+
+        let received = CarbonsPlugin::carbon_message(&account, &stanza("<message xmlns='jabber:client' from='me@server.tld' to='me@server.tld/aparte'><received xmlns='urn:xmpp:carbons:2'><forwarded xmlns='urn:xmpp:forward:0'><message xmlns='jabber:client' type='chat' id='received' from='contact@server.tld/phone' to='me@server.tld/phone'><body>Hi</body></message></forwarded></received></message>")).unwrap();
+        match &received {
+            Message::Incoming(message::XmppMessage::Chat(message)) => assert_eq!(message.from, BareJid::from_str("contact@server.tld").unwrap()),
+            _ => panic!("Expected incoming chat message"),
+        }
+    }
+
+    #[test]
+    fn test_spoofed_carbon_is_ignored() {
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let spoofed = stanza("<message xmlns='jabber:client' from='evil@server.tld/bot' to='me@server.tld/aparte'><received xmlns='urn:xmpp:carbons:2'><forwarded xmlns='urn:xmpp:forward:0'><message xmlns='jabber:client' type='chat' from='contact@server.tld/phone' to='me@server.tld'><body>Send me your password</body></message></forwarded></received></message>");
+
+        assert!(CarbonsPlugin::carbon_message(&account, &spoofed).is_none());
+
+        let resource = stanza("<message xmlns='jabber:client' from='me@server.tld/other' to='me@server.tld/aparte'><received xmlns='urn:xmpp:carbons:2'><forwarded xmlns='urn:xmpp:forward:0'><message xmlns='jabber:client' type='chat' from='contact@server.tld/phone' to='me@server.tld'><body>Send me your password</body></message></forwarded></received></message>");
+        assert!(CarbonsPlugin::carbon_message(&account, &resource).is_none());
+    }
+}
@@ -1,8 +1,13 @@
+use futures::Future;
 use std::fmt;
 use std::rc::Rc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
 use uuid::Uuid;
-use xmpp_parsers::{Element, roster, ns, Jid, BareJid, presence};
+use xmpp_parsers::{roster, Element, Jid, BareJid, FullJid, presence};
+use xmpp_parsers::presence::{Presence, Type as PresenceType};
 use xmpp_parsers::iq::{Iq, IqType};
 use std::convert::TryFrom;
 
@@ -15,32 +20,230 @@ impl From<roster::Group> for contact::Group {
     }
 }
 
-impl From<roster::Item> for contact::Contact {
-    fn from(item: roster::Item) -> Self {
+impl From<(BareJid, roster::Item)> for contact::Contact {
+    fn from((account, item): (BareJid, roster::Item)) -> Self {
         let mut groups = Vec::new();
         for group in item.groups {
             groups.push(group.into());
         }
 
         Self {
+            account: account,
             jid: item.jid.clone(),
             name: item.name.clone(),
             subscription: item.subscription.clone(),
             presence: contact::Presence::Unavailable,
+            status: None,
+            resources: HashMap::new(),
             groups: groups,
         }
     }
 }
 
+const NS_ROSTER_VER: &str = "urn:xmpp:features:rosterver";
+
 pub struct ContactPlugin {
-    pub contacts: HashMap<BareJid, contact::Contact>,
+    /// Contacts of every account, by account and contact
+    pub contacts: HashMap<(BareJid, BareJid), contact::Contact>,
+    /// Roster of each account as last received, persisted with its version
+    rosters: HashMap<BareJid, roster::Roster>,
+    cache_dir: PathBuf,
+    /// Pending subscription requests, by account and contact
+    pub subscription_requests: HashMap<(BareJid, BareJid), contact::SubscriptionRequest>,
+    /// Contacts approved before they asked, for servers without pre-approval support, by account.
+    /// Persisted next to the roster cache.
+    pre_approved: HashSet<(BareJid, BareJid)>,
 }
 
 impl ContactPlugin {
-    fn request(&self) -> Element {
+    fn request(&self, ver: Option<String>) -> Iq {
         let id = Uuid::new_v4().to_hyphenated().to_string();
-        let iq = Iq::from_get(id, roster::Roster { ver: None, items: Vec::new() });
-        iq.into()
+        Iq::from_get(id, roster::Roster { ver: ver, items: Vec::new() })
+    }
+
+    fn cache_path(&self, account: &BareJid) -> PathBuf {
+        self.cache_dir.join(format!("{}.xml", account))
+    }
+
+    fn load(&self, account: &BareJid) -> Option<roster::Roster> {
+        let content = fs::read_to_string(self.cache_path(account)).ok()?;
+        let element = Element::from_str(&content).ok()?;
+        roster::Roster::try_from(element).ok()
+    }
+
+    fn pre_approved_path(&self, account: &BareJid) -> PathBuf {
+        self.cache_dir.join(format!("{}.pre-approved", account))
+    }
+
+    /// Read the pre-approvals of an account saved in a previous session, one JID per line
+    fn load_pre_approved(&mut self, account: &BareJid) {
+        let content = fs::read_to_string(self.pre_approved_path(account)).unwrap_or_default();
+        for jid in content.lines().filter_map(|line| BareJid::from_str(line).ok()) {
+            self.pre_approved.insert((account.clone(), jid));
+        }
+    }
+
+    fn save_pre_approved(&self, account: &BareJid) {
+        let content = self.pre_approved.iter()
+            .filter(|(pre_approved, _)| pre_approved == account)
+            .map(|(_, jid)| format!("{}\n", jid))
+            .collect::<String>();
+        let result = fs::create_dir_all(&self.cache_dir)
+            .and_then(|_| fs::write(self.pre_approved_path(account), content));
+        if let Err(err) = result {
+            warn!("Cannot save pre-approvals of {}: {}", account, err);
+        }
+    }
+
+    /// Approve a contact's subscription before it asks for it
+    pub fn pre_approve(&mut self, account: &FullJid, jid: &BareJid) {
+        let account: BareJid = account.clone().into();
+        if self.pre_approved.insert((account.clone(), jid.clone())) {
+            self.save_pre_approved(&account);
+        }
+    }
+
+    /// Forget a pre-approval, returns whether there was one
+    pub fn remove_pre_approval(&mut self, account: &FullJid, jid: &BareJid) -> bool {
+        let account: BareJid = account.clone().into();
+        let removed = self.pre_approved.remove(&(account.clone(), jid.clone()));
+        if removed {
+            self.save_pre_approved(&account);
+        }
+        removed
+    }
+
+    fn save(&self, account: &BareJid) {
+        if let Some(roster) = self.rosters.get(account) {
+            let element: Element = roster.clone().into();
+            let result = fs::create_dir_all(&self.cache_dir)
+                .and_then(|_| fs::write(self.cache_path(account), String::from(&element)));
+            if let Err(err) = result {
+                warn!("Cannot save roster of {}: {}", account, err);
+            }
+        }
+    }
+
+    fn update_contact(&mut self, account: &FullJid, item: roster::Item) -> Event {
+        let mut contact: contact::Contact = (account.clone().into(), item).into();
+        let key = (contact.account.clone(), contact.jid.clone());
+        if let Some(existing) = self.contacts.get(&key) {
+            contact.presence = existing.presence.clone();
+            contact.status = existing.status.clone();
+            contact.resources = existing.resources.clone();
+        }
+        self.contacts.insert(key, contact.clone());
+        Event::Contact(account.clone(), contact)
+    }
+
+    fn remove_contact(&mut self, account: &FullJid, jid: &BareJid) -> Option<Event> {
+        self.contacts.remove(&(account.clone().into(), jid.clone())).map(|contact| Event::ContactRemoved(account.clone(), contact))
+    }
+
+    /// Contact of an account
+    pub fn contact(&self, account: &FullJid, jid: &BareJid) -> Option<&contact::Contact> {
+        self.contacts.get(&(account.clone().into(), jid.clone()))
+    }
+
+    /// JIDs of the contacts of every account, for completion
+    pub fn jids(&self) -> Vec<String> {
+        let mut jids = self.contacts.keys().map(|(_, jid)| jid.to_string()).collect::<Vec<_>>();
+        jids.sort();
+        jids.dedup();
+        jids
+    }
+
+    /// Replace the whole roster of an account, contacts missing from it are removed
+    fn set_roster(&mut self, account: &FullJid, roster: roster::Roster) -> Vec<Event> {
+        let bare: BareJid = account.clone().into();
+        let mut events = Vec::new();
+
+        if let Some(previous) = self.rosters.remove(&bare) {
+            for item in previous.items.iter().filter(|item| !roster.items.iter().any(|new| new.jid == item.jid)) {
+                events.extend(self.remove_contact(account, &item.jid));
+            }
+        }
+
+        for item in roster.items.iter() {
+            events.push(self.update_contact(account, item.clone()));
+        }
+
+        self.rosters.insert(bare.clone(), roster);
+        self.save(&bare);
+
+        events
+    }
+
+    /// Apply a roster push from the server
+    fn push(&mut self, account: &FullJid, push: roster::Roster) -> Vec<Event> {
+        let bare: BareJid = account.clone().into();
+        let mut events = Vec::new();
+        let mut roster = self.rosters.remove(&bare).unwrap_or(roster::Roster { ver: None, items: Vec::new() });
+
+        for item in push.items {
+            roster.items.retain(|existing| existing.jid != item.jid);
+            if item.subscription == roster::Subscription::Remove {
+                events.extend(self.remove_contact(account, &item.jid));
+            } else {
+                roster.items.push(item.clone());
+                events.push(self.update_contact(account, item));
+            }
+        }
+
+        if push.ver.is_some() {
+            roster.ver = push.ver;
+        }
+
+        self.rosters.insert(bare.clone(), roster);
+        self.save(&bare);
+
+        events
+    }
+
+    /// Roster item describing a known contact, to be modified and sent back in a roster set
+    pub fn item(contact: &contact::Contact) -> roster::Item {
+        roster::Item {
+            jid: contact.jid.clone(),
+            name: contact.name.clone(),
+            subscription: roster::Subscription::None,
+            ask: roster::Ask::None,
+            groups: contact.groups.iter().map(|group| roster::Group(group.0.clone())).collect(),
+        }
+    }
+
+    /// Groups currently used by at least one contact
+    pub fn groups(&self) -> Vec<String> {
+        let mut groups = self.contacts.values()
+            .flat_map(|contact| contact.groups.iter().map(|group| group.0.clone()))
+            .collect::<Vec<_>>();
+        groups.sort();
+        groups.dedup();
+        groups
+    }
+
+    /// Send a roster set for the given item, the server answers with a roster push
+    pub fn set(aparte: Rc<Aparte>, account: &FullJid, item: roster::Item) {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let jid = item.jid.clone();
+        let iq = Iq::from_set(id, roster::Roster { ver: None, items: vec![item] });
+
+        let error_aparte = Rc::clone(&aparte);
+        tokio::runtime::current_thread::spawn(Rc::clone(&aparte).iq(account, iq).map(|_| {
+            debug!("Roster updated");
+        }).map_err(move |err| {
+            error_aparte.log(format!("Cannot update {} in roster: {}", jid, err));
+        }));
+    }
+
+    fn handle_roster(aparte: Rc<Aparte>, account: &FullJid, roster: roster::Roster) {
+        let events = {
+            let mut plugin = aparte.get_plugin_mut::<ContactPlugin>().unwrap();
+            plugin.set_roster(account, roster)
+        };
+
+        for event in events {
+            Rc::clone(&aparte).event(event);
+        }
     }
 }
 
@@ -48,44 +251,146 @@ impl Plugin for ContactPlugin {
     fn new() -> ContactPlugin {
         Self {
             contacts: HashMap::new(),
+            rosters: HashMap::new(),
+            cache_dir: PathBuf::new(),
+            subscription_requests: HashMap::new(),
+            pre_approved: HashSet::new(),
         }
     }
 
-    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        self.cache_dir = aparte.data_dir.join("roster");
         Ok(())
     }
 
     fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
         match event {
-            Event::Connected(_jid) => aparte.send(self.request()),
-            Event::Iq(iq) => {
-                if let IqType::Result(Some(payload)) = iq.payload.clone() {
-                    if payload.is("query", ns::ROSTER) {
-                        if let Ok(roster) = roster::Roster::try_from(payload.clone()) {
-                            for item in roster.items {
-                                let contact: contact::Contact = item.clone().into();
-                                self.contacts.insert(contact.jid.clone(), contact.clone());
-                                Rc::clone(&aparte).event(Event::Contact(contact.clone()));
+            Event::Connected(account) => {
+                // With roster versioning the server only sends what changed since the cached
+                // version: nothing at all, or pushes following an empty result
+                let versioning = match aparte.stream_features(account) {
+                    Some(features) => features.has_child("ver", NS_ROSTER_VER),
+                    None => false,
+                };
+                let bare: BareJid = account.clone().into();
+                let cached = match versioning {
+                    true => self.load(&bare),
+                    false => None,
+                };
+                let ver = match (versioning, &cached) {
+                    (true, Some(cached)) => Some(cached.ver.clone().unwrap_or_default()),
+                    (true, None) => Some(String::new()),
+                    (false, _) => None,
+                };
+                if let Some(cached) = cached {
+                    self.rosters.insert(bare.clone(), cached);
+                }
+                self.load_pre_approved(&bare);
+
+                let roster_aparte = Rc::clone(&aparte);
+                let error_aparte = Rc::clone(&aparte);
+                let account = account.clone();
+                tokio::runtime::current_thread::spawn(Rc::clone(&aparte).iq(&account, self.request(ver)).map(move |payload| {
+                    match payload.map(roster::Roster::try_from) {
+                        Some(Ok(roster)) => ContactPlugin::handle_roster(roster_aparte, &account, roster),
+                        Some(Err(err)) => warn!("Invalid roster: {}", err),
+                        None => {
+                            // The cached roster is up to date, along with the pushes received
+                            // while waiting for this result
+                            let current = roster_aparte.get_plugin::<ContactPlugin>().unwrap().rosters.get(&bare).cloned();
+                            match current {
+                                Some(current) => ContactPlugin::handle_roster(roster_aparte, &account, current),
+                                None => warn!("Empty roster result"),
                             }
+                        },
+                    }
+                }).map_err(move |err| {
+                    error_aparte.log(format!("Cannot get roster: {}", err));
+                }));
+            },
+            Event::Iq(account, iq) => {
+                if let IqType::Set(payload) = &iq.payload {
+                    if let Ok(push) = roster::Roster::try_from(payload.clone()) {
+                        // Only our own server is allowed to push roster changes
+                        let bare: BareJid = account.clone().into();
+                        match &iq.from {
+                            None => {},
+                            Some(Jid::Bare(from)) if from == &bare => {},
+                            Some(from) => {
+                                warn!("Ignoring roster push from {}", from);
+                                return;
+                            },
+                        }
+
+                        for event in self.push(account, push) {
+                            Rc::clone(&aparte).event(event);
                         }
+
+                        let mut result = Iq::from_result(iq.id.clone(), None::<roster::Roster>);
+                        result.to = iq.from.clone();
+                        aparte.send(account, result.into());
                     }
                 }
             },
-            Event::Presence(presence) => {
+            Event::Presence(account, presence) => {
                 if let Some(from) = &presence.from {
-                    let jid = match from {
-                        Jid::Bare(jid) => jid.clone(),
-                        Jid::Full(jid) => jid.clone().into(),
+                    let (jid, resource) = match from {
+                        Jid::Bare(jid) => (jid.clone(), String::new()),
+                        Jid::Full(jid) => (jid.clone().into(), jid.resource.clone()),
                     };
-                    if let Some(contact) = self.contacts.get_mut(&jid) {
-                        contact.presence = match presence.show {
-                            Some(presence::Show::Away) => contact::Presence::Away,
-                            Some(presence::Show::Chat) => contact::Presence::Chat,
-                            Some(presence::Show::Dnd) => contact::Presence::Dnd,
-                            Some(presence::Show::Xa) => contact::Presence::Xa,
-                            None => contact::Presence::Available,
-                        };
-                        Rc::clone(&aparte).event(Event::ContactUpdate(contact.clone()));
+                    let status = presence.statuses.values().next().cloned();
+
+                    match presence.type_ {
+                        PresenceType::None | PresenceType::Unavailable => {
+                            if let Some(contact) = self.contacts.get_mut(&(account.clone().into(), jid.clone())) {
+                                let show = match presence.show {
+                                    Some(presence::Show::Away) => contact::Presence::Away,
+                                    Some(presence::Show::Chat) => contact::Presence::Chat,
+                                    Some(presence::Show::Dnd) => contact::Presence::Dnd,
+                                    Some(presence::Show::Xa) => contact::Presence::Xa,
+                                    None => contact::Presence::Available,
+                                };
+                                let available = match presence.type_ {
+                                    PresenceType::Unavailable => None,
+                                    _ => Some(contact::Resource {
+                                        presence: show,
+                                        status: status,
+                                        priority: presence.priority,
+                                    }),
+                                };
+                                contact.set_resource(resource, available);
+                                Rc::clone(&aparte).event(Event::ContactUpdate(account.clone(), contact.clone()));
+                            }
+                        },
+                        PresenceType::Subscribe => {
+                            if self.remove_pre_approval(account, &jid) {
+                                let approval = Presence::new(PresenceType::Subscribed).with_to(Jid::Bare(jid.clone()));
+                                aparte.send(account, approval.into());
+                                Rc::clone(&aparte).log(format!("Approved pre-approved subscription request of {}", jid));
+                            } else {
+                                let request = contact::SubscriptionRequest {
+                                    account: account.clone(),
+                                    jid: jid.clone(),
+                                    status: status,
+                                };
+                                self.subscription_requests.insert((account.clone().into(), jid.clone()), request.clone());
+                                Rc::clone(&aparte).log(format!("{} wants to subscribe to your presence, use /subscription approve {} or /subscription deny {}", jid, jid, jid));
+                                Rc::clone(&aparte).event(Event::SubscriptionRequest(account.clone(), request));
+                            }
+                        },
+                        PresenceType::Unsubscribe => {
+                            if let Some(request) = self.subscription_requests.remove(&(account.clone().into(), jid.clone())) {
+                                Rc::clone(&aparte).event(Event::SubscriptionRequestRemoved(account.clone(), request));
+                            }
+                            Rc::clone(&aparte).log(format!("{} unsubscribed from your presence", jid));
+                        },
+                        PresenceType::Subscribed => {
+                            Rc::clone(&aparte).log(format!("{} approved your subscription request", jid));
+                        },
+                        PresenceType::Unsubscribed => {
+                            Rc::clone(&aparte).log(format!("{} denied or cancelled your subscription", jid));
+                        },
+                        _ => {},
                     }
                 }
             },
@@ -99,3 +404,64 @@ impl fmt::Display for ContactPlugin {
         write!(f, "Contact management")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(jid: &str, name: &str) -> roster::Item {
+        roster::Item {
+            jid: BareJid::from_str(jid).unwrap(),
+            name: Some(name.to_string()),
+            subscription: roster::Subscription::Both,
+            ask: roster::Ask::None,
+            groups: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_pre_approvals_are_persisted() {
+        let cache_dir = std::env::temp_dir().join(format!("aparte-roster-{}", Uuid::new_v4()));
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let jid = BareJid::from_str("contact@server.tld").unwrap();
+
+        let mut plugin = ContactPlugin::new();
+        plugin.cache_dir = cache_dir.clone();
+        plugin.pre_approve(&account, &jid);
+
+        let mut restarted = ContactPlugin::new();
+        restarted.cache_dir = cache_dir.clone();
+        restarted.load_pre_approved(&account.clone().into());
+        assert!(restarted.remove_pre_approval(&account, &jid));
+        assert!(!restarted.remove_pre_approval(&account, &jid));
+
+        let mut restarted = ContactPlugin::new();
+        restarted.cache_dir = cache_dir.clone();
+        restarted.load_pre_approved(&account.clone().into());
+        assert!(!restarted.remove_pre_approval(&account, &jid));
+
+        let _ = fs::remove_dir_all(&cache_dir);
+    }
+
+    #[test]
+    fn test_contacts_are_kept_per_account() {
+        let mut plugin = ContactPlugin::new();
+        plugin.cache_dir = std::env::temp_dir().join(format!("aparte-roster-{}", Uuid::new_v4()));
+        let work = FullJid::from_str("me@work.tld/aparte").unwrap();
+        let home = FullJid::from_str("me@home.tld/aparte").unwrap();
+        let jid = BareJid::from_str("contact@server.tld").unwrap();
+
+        plugin.push(&work, roster::Roster { ver: None, items: vec![item("contact@server.tld", "Colleague")] });
+        plugin.push(&home, roster::Roster { ver: None, items: vec![item("contact@server.tld", "Friend")] });
+        assert_eq!(plugin.contact(&work, &jid).unwrap().name, Some("Colleague".to_string()));
+        assert_eq!(plugin.contact(&home, &jid).unwrap().name, Some("Friend".to_string()));
+
+        let mut removal = item("contact@server.tld", "Friend");
+        removal.subscription = roster::Subscription::Remove;
+        plugin.push(&home, roster::Roster { ver: None, items: vec![removal] });
+        assert!(plugin.contact(&home, &jid).is_none());
+        assert!(plugin.contact(&work, &jid).is_some());
+
+        let _ = fs::remove_dir_all(&plugin.cache_dir);
+    }
+}
@@ -1,17 +1,405 @@
+use futures::Future;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
 use std::rc::Rc;
-use xmpp_parsers::{Jid, BareJid, muc};
+use uuid::Uuid;
+use xmpp_parsers::{Jid, BareJid, FullJid, muc};
+use xmpp_parsers::data_forms::{DataForm, DataFormType};
+use xmpp_parsers::iq::Iq;
+use xmpp_parsers::muc::user::Status;
+use xmpp_parsers::muc::muc::History;
+use xmpp_parsers::presence::{Presence, Type as PresenceType};
+use xmpp_parsers::stanza_error::{DefinedCondition, StanzaError};
 
-use crate::core::{Plugin, Aparte, Event};
+use crate::core::{Plugin, Aparte, Event, IqError};
 use crate::conversation;
+use crate::form;
+use crate::muc::{Admin, AdminItem, Owner};
+use crate::plugins::presence::PresencePlugin;
+
+const DEFAULT_HISTORY: u32 = 20;
+/// Alternate nicks tried on conflict are built by appending this, up to MAX_NICK_ATTEMPTS times
+const NICK_SUFFIX: char = '_';
+const MAX_NICK_ATTEMPTS: usize = 3;
 
 pub struct ConversationPlugin {
-    conversations: HashMap<String, conversation::Conversation>,
+    /// Open conversations, by account and contact or channel
+    conversations: HashMap<(BareJid, BareJid), conversation::Conversation>,
 }
 
 impl ConversationPlugin {
+    /// Our occupant JID in each channel joined with the given account
+    pub fn channels(&self, account: &FullJid) -> Vec<FullJid> {
+        self.conversations.values().filter_map(|conversation| match conversation {
+            conversation::Conversation::Channel(channel) if &channel.account == account => {
+                Some(channel.jid.clone().with_resource(channel.nick.clone()))
+            },
+            _ => None,
+        }).collect()
+    }
+
+    pub fn channel(&self, account: &FullJid, jid: &BareJid) -> Option<&conversation::Channel> {
+        match self.conversations.get(&(account.clone().into(), jid.clone())) {
+            Some(conversation::Conversation::Channel(channel)) => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// Send a muc#admin request, failures are reported in the channel window
+    pub fn admin(aparte: Rc<Aparte>, account: &FullJid, channel: &BareJid, item: AdminItem, action: String) {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Iq::from_set(id, Admin { items: vec![item] }).with_to(Jid::Bare(channel.clone()));
+
+        let error_aparte = Rc::clone(&aparte);
+        let account = account.clone();
+        let channel = channel.clone();
+        tokio::runtime::current_thread::spawn(Rc::clone(&aparte).iq(&account, iq).map(|_| {
+            debug!("Admin request succeeded");
+        }).map_err(move |err| {
+            error_aparte.event(Event::Notice {
+                account: account,
+                conversation: channel,
+                text: format!("Cannot {}: {}", action, Self::admin_error(&err)),
+            });
+        }));
+    }
+
+    /// Fetch the configuration form of a channel we own
+    pub fn room_config(aparte: Rc<Aparte>, account: &FullJid, channel: &BareJid) {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Iq::from_get(id, Owner { form: None }).with_to(Jid::Bare(channel.clone()));
+
+        let form_aparte = Rc::clone(&aparte);
+        let error_aparte = Rc::clone(&aparte);
+        let form_account = account.clone();
+        let form_channel = channel.clone();
+        let account = account.clone();
+        let channel = channel.clone();
+        tokio::runtime::current_thread::spawn(Rc::clone(&aparte).iq(&account, iq).and_then(|payload| {
+            match payload.map(Owner::try_from) {
+                Some(Ok(Owner { form: Some(form) })) => Ok(form),
+                _ => Err(IqError::Invalid),
+            }
+        }).map(move |form| {
+            form_aparte.event(Event::RoomConfig { account: form_account, channel: form_channel, form: form });
+        }).map_err(move |err| {
+            error_aparte.event(Event::Notice {
+                account: account,
+                conversation: channel,
+                text: format!("Cannot get configuration: {}", Self::admin_error(&err)),
+            });
+        }));
+    }
+
+    /// Submit a filled configuration form, or cancel the configuration when there is none
+    pub fn submit_room_config(aparte: Rc<Aparte>, account: &FullJid, channel: &BareJid, form: Option<DataForm>) {
+        let form = match form {
+            Some(form) => form::submission(&form),
+            None => DataForm {
+                type_: DataFormType::Cancel,
+                form_type: None,
+                title: None,
+                instructions: None,
+                fields: Vec::new(),
+            },
+        };
+        let cancel = form.type_ == DataFormType::Cancel;
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Iq::from_set(id, Owner { form: Some(form) }).with_to(Jid::Bare(channel.clone()));
+
+        let notice_aparte = Rc::clone(&aparte);
+        let account = account.clone();
+        let channel = channel.clone();
+        tokio::runtime::current_thread::spawn(Rc::clone(&aparte).iq(&account, iq).then(move |result| {
+            let text = match (result, cancel) {
+                (Ok(_), false) => format!("Configuration saved"),
+                (Ok(_), true) => format!("Configuration cancelled"),
+                (Err(err), _) => format!("Cannot configure {}: {}", channel, Self::admin_error(&err)),
+            };
+            notice_aparte.event(Event::Notice {
+                account: account,
+                conversation: channel,
+                text: text,
+            });
+            Ok(())
+        }));
+    }
+
+    /// Whether a presence tells that we just created the channel, which stays locked until configured
+    fn room_created(presence: &Presence) -> bool {
+        presence.payloads.iter().filter_map(|payload| muc::user::MucUser::try_from(payload.clone()).ok()).any(|muc_user| {
+            muc_user.status.contains(&Status::SelfPresence) && muc_user.status.contains(&Status::RoomHasBeenCreated)
+        })
+    }
+
+    fn admin_error(err: &IqError) -> String {
+        let reason = match err {
+            IqError::Stanza(error) => match error.defined_condition {
+                DefinedCondition::Forbidden => "you don't have the required role or affiliation",
+                DefinedCondition::NotAllowed => "the room doesn't allow it",
+                DefinedCondition::ItemNotFound => "no such occupant",
+                DefinedCondition::Conflict => "it would leave the room without owner",
+                DefinedCondition::NotAcceptable => "invalid request",
+                _ => return err.to_string(),
+            },
+            _ => return err.to_string(),
+        };
+
+        match err {
+            IqError::Stanza(error) if !error.texts.is_empty() => format!("{} ({})", reason, error.texts.values().next().unwrap()),
+            _ => reason.to_string(),
+        }
+    }
+
+    /// Nick to use in channels when none is given
+    pub fn default_nick(aparte: &Aparte, account: &FullJid) -> String {
+        match aparte.account_config(account).and_then(|config| config.nick.clone()) {
+            Some(nick) => nick,
+            None => account.node.clone().unwrap_or_else(|| account.resource.clone()),
+        }
+    }
+
+    /// Presence joining a channel with our current status, its password and history limits
+    pub fn join_presence(aparte: &Aparte, account: &FullJid, to: &FullJid, password: Option<String>) -> Presence {
+        let history = aparte.account_config(account).and_then(|config| config.history).unwrap_or(DEFAULT_HISTORY);
+        let mut muc = muc::Muc::new().with_history(History::new().with_maxstanzas(history));
+        if let Some(password) = password {
+            muc = muc.with_password(password);
+        }
+
+        let mut presence = aparte.get_plugin::<PresencePlugin>().unwrap().presence(aparte, account);
+        presence = presence.with_to(Jid::Full(to.clone()));
+        presence = presence.with_from(Jid::Full(account.clone()));
+        presence.add_payload(muc);
+        presence
+    }
+
+    /// Send our unavailable presence to a channel, with an optional reason
+    pub fn leave(aparte: &Aparte, account: &FullJid, channel: &BareJid, reason: Option<String>) {
+        let nick = match aparte.get_plugin::<ConversationPlugin>().unwrap().channel(account, channel) {
+            Some(channel) => channel.nick.clone(),
+            None => return,
+        };
+
+        let mut presence = Presence::new(PresenceType::Unavailable);
+        presence = presence.with_to(Jid::Full(channel.clone().with_resource(nick)));
+        presence = presence.with_from(Jid::Full(account.clone()));
+        if let Some(reason) = reason {
+            presence.set_status("", reason);
+        }
+        aparte.send(account, presence.into());
+    }
+
+    /// Join a channel and open its window
+    pub fn join(aparte: Rc<Aparte>, account: &FullJid, channel: FullJid, password: Option<String>) {
+        let presence = Self::join_presence(&aparte, account, &channel, password.clone());
+        aparte.send(account, presence.into());
+        aparte.event(Event::Join {
+            account: account.clone(),
+            channel: channel,
+            password: password,
+        });
+    }
+
+    /// Forget the occupants of the channels of an account, those who left while we were offline
+    /// won't tell. The channels send the current ones again once joined.
+    fn forget_occupants(&mut self, account: &FullJid) -> Vec<Event> {
+        let mut events = Vec::new();
+        for (_, conversation) in self.conversations.iter_mut() {
+            if let conversation::Conversation::Channel(channel) = conversation {
+                if &channel.account == account {
+                    channel.joined = false;
+                    for (_, occupant) in channel.occupants.drain() {
+                        events.push(Event::OccupantRemoved {
+                            account: account.clone(),
+                            conversation: channel.jid.clone(),
+                            occupant: occupant,
+                        });
+                    }
+                }
+            }
+        }
+
+        events
+    }
+
+    fn rejoin(&mut self, aparte: Rc<Aparte>, account: &FullJid) {
+        for event in self.forget_occupants(account) {
+            Rc::clone(&aparte).event(event);
+        }
+
+        for (_, conversation) in self.conversations.iter() {
+            if let conversation::Conversation::Channel(channel) = conversation {
+                if &channel.account == account {
+                    let to = channel.jid.clone().with_resource(channel.nick.clone());
+                    let presence = Self::join_presence(&aparte, account, &to, channel.password.clone());
+                    aparte.send(account, presence.into());
+                }
+            }
+        }
+    }
+
+    /// Next nick to try when ours is already used, None once enough alternatives failed
+    fn alternate_nick(nick: &str) -> Option<String> {
+        match nick.chars().rev().take_while(|c| *c == NICK_SUFFIX).count() {
+            attempts if attempts < MAX_NICK_ATTEMPTS => Some(format!("{}{}", nick, NICK_SUFFIX)),
+            _ => None,
+        }
+    }
+
+    /// Explain why joining a channel failed, retrying with an alternate nick on conflict
+    fn handle_join_error(&mut self, aparte: Rc<Aparte>, account: &FullJid, from: &FullJid, presence: &Presence) {
+        let channel_jid: BareJid = from.clone().into();
+        let channel = match self.conversations.get_mut(&(account.clone().into(), channel_jid.clone())) {
+            Some(conversation::Conversation::Channel(channel)) if !channel.joined => channel,
+            _ => return,
+        };
+
+        let error = match presence.payloads.iter().find_map(|payload| StanzaError::try_from(payload.clone()).ok()) {
+            Some(error) => error,
+            None => return,
+        };
+
+        let alternate = Self::alternate_nick(&channel.nick);
+        let text = match (error.defined_condition.clone(), alternate) {
+            (DefinedCondition::Conflict, Some(nick)) => {
+                let to = channel.jid.clone().with_resource(nick.clone());
+                let text = format!("Nick {} is already in use, joining as {}", channel.nick, nick);
+                channel.nick = nick;
+                aparte.send(account, Self::join_presence(&aparte, account, &to, channel.password.clone()).into());
+                text
+            },
+            (DefinedCondition::Conflict, None) => format!("Nick {} is already in use, join again with another nick", channel.nick),
+            (DefinedCondition::NotAuthorized, _) => format!("A valid password is required, use /join {} <nick> <password>", channel.jid),
+            (DefinedCondition::RegistrationRequired, _) => format!("Only members can join this channel"),
+            (DefinedCondition::Forbidden, _) => format!("You are banned from this channel"),
+            (DefinedCondition::ServiceUnavailable, _) => format!("This channel is full"),
+            (DefinedCondition::ItemNotFound, _) => format!("This channel is locked"),
+            (DefinedCondition::NotAcceptable, _) => format!("Nick {} is not allowed in this channel", channel.nick),
+            (_, _) => format!("Cannot join: {}", IqError::Stanza(error)),
+        };
+
+        Rc::clone(&aparte).event(Event::Notice {
+            account: account.clone(),
+            conversation: channel_jid,
+            text: text,
+        });
+    }
+
+    /// Track occupants joining, leaving, changing nick or being kicked from a channel
+    fn handle_occupant_presence(&mut self, account: &FullJid, from: &FullJid, presence: &Presence) -> Vec<Event> {
+        let mut events = Vec::new();
+        let channel_jid: BareJid = from.clone().into();
+        let channel = match self.conversations.get_mut(&(account.clone().into(), channel_jid.clone())) {
+            Some(conversation::Conversation::Channel(channel)) => channel,
+            _ => return events,
+        };
+
+        let muc_user = match presence.payloads.iter().find_map(|payload| muc::user::MucUser::try_from(payload.clone()).ok()) {
+            Some(muc_user) => muc_user,
+            None => return events,
+        };
+
+        let nick = from.resource.clone();
+        let is_self = muc_user.status.contains(&Status::SelfPresence) || (!channel.joined && nick == channel.nick);
+        let who = match is_self {
+            true => String::from("You"),
+            false => nick.clone(),
+        };
+        let item = muc_user.items.into_iter().next();
+        let reason = match item.as_ref().and_then(|item| item.reason.as_ref()) {
+            Some(reason) => format!(" ({})", reason.0),
+            None => String::new(),
+        };
+        let notice = |text: String| Event::Notice {
+            account: account.clone(),
+            conversation: channel_jid.clone(),
+            text: text,
+        };
+
+        match presence.type_ {
+            PresenceType::Unavailable => {
+                let occupant = channel.occupants.remove(&nick);
+                if let Some(occupant) = &occupant {
+                    events.push(Event::OccupantRemoved {
+                        account: account.clone(),
+                        conversation: channel_jid.clone(),
+                        occupant: occupant.clone(),
+                    });
+                }
+
+                let new_nick = item.as_ref().and_then(|item| item.nick.clone());
+                if let (true, Some(new_nick)) = (muc_user.status.contains(&Status::NewNick), new_nick) {
+                    if is_self {
+                        channel.nick = new_nick.clone();
+                    }
+                    // Keep the occupant under its new nick so the following presence isn't seen as a join
+                    if let Some(mut occupant) = occupant {
+                        occupant.nick = new_nick.clone();
+                        channel.occupants.insert(new_nick.clone(), occupant.clone());
+                        events.push(Event::Occupant {
+                            account: account.clone(),
+                            conversation: channel_jid.clone(),
+                            occupant: occupant,
+                        });
+                    }
+                    events.push(notice(format!("{} {} now known as {}", who, if is_self { "are" } else { "is" }, new_nick)));
+                } else {
+                    let verb = if is_self { "have" } else { "has" };
+                    let text = if muc_user.status.contains(&Status::Kicked) {
+                        format!("{} {} been kicked{}", who, verb, reason)
+                    } else if muc_user.status.contains(&Status::Banned) {
+                        format!("{} {} been banned{}", who, verb, reason)
+                    } else {
+                        match presence.statuses.values().next() {
+                            Some(status) => format!("{} left ({})", who, status),
+                            None => format!("{} left", who),
+                        }
+                    };
+                    events.push(notice(text));
+
+                    if is_self {
+                        channel.joined = false;
+                    }
+                }
+            },
+            PresenceType::None => {
+                let item = match item {
+                    Some(item) if item.role != muc::user::Role::None => item,
+                    _ => return events,
+                };
+
+                let occupant = conversation::Occupant {
+                    nick: nick.clone(),
+                    jid: item.jid.map(|full| full.into()),
+                    affiliation: item.affiliation.into(),
+                    role: item.role.into(),
+                };
+
+                if is_self {
+                    // Our nick may have been changed by the service (status 210)
+                    channel.nick = nick.clone();
+                    if !channel.joined {
+                        channel.joined = true;
+                        events.push(notice(format!("You joined {} as {}", channel_jid, nick)));
+                    }
+                } else if channel.joined && !channel.occupants.contains_key(&nick) {
+                    events.push(notice(format!("{} joined", nick)));
+                }
+
+                channel.occupants.insert(nick, occupant.clone());
+                events.push(Event::Occupant {
+                    account: account.clone(),
+                    conversation: channel_jid.clone(),
+                    occupant: occupant,
+                });
+            },
+            _ => {},
+        }
+
+        events
+    }
 }
 
 impl From<muc::user::Role> for conversation::Role {
@@ -50,44 +438,66 @@ impl Plugin for ConversationPlugin {
 
     fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
         match event {
-            Event::Chat(jid) => {
+            Event::Connected(account) => self.rejoin(aparte, account),
+            Event::ConversationClosed { account, conversation } => {
+                self.conversations.remove(&(account.clone().into(), conversation.clone()));
+            },
+            Event::Chat { account, contact } => {
                 let conversation = conversation::Conversation::Chat(conversation::Chat {
-                    contact: jid.clone(),
+                    account: account.clone(),
+                    contact: contact.clone(),
                 });
-                self.conversations.insert(jid.to_string(), conversation);
+                self.conversations.insert((account.clone().into(), contact.clone()), conversation);
             },
-            Event::Join(jid) => {
-                let channel_jid: BareJid = jid.clone().into();
+            Event::Join { account, channel, password } => {
+                let channel_jid: BareJid = channel.clone().into();
                 let conversation = conversation::Conversation::Channel(conversation::Channel {
+                    account: account.clone(),
                     jid: channel_jid.clone(),
-                    nick: jid.resource.clone(),
+                    nick: channel.resource.clone(),
                     name: None,
                     occupants: HashMap::new(),
+                    subject: None,
+                    password: password.clone(),
+                    joined: false,
                 });
-                self.conversations.insert(channel_jid.to_string(), conversation);
+                self.conversations.insert((account.clone().into(), channel_jid), conversation);
             },
-            Event::Presence(presence) => {
+            Event::Subject { account, channel: channel_jid, subject, by } => {
+                if let Some(conversation::Conversation::Channel(channel)) = self.conversations.get_mut(&(account.clone().into(), channel_jid.clone())) {
+                    channel.subject = subject.clone();
+                    let text = match (by, subject) {
+                        (Some(by), Some(subject)) => format!("{} changed the subject to: {}", by, subject),
+                        (Some(by), None) => format!("{} removed the subject", by),
+                        (None, Some(subject)) => format!("Subject: {}", subject),
+                        (None, None) => format!("No subject"),
+                    };
+                    Rc::clone(&aparte).event(Event::Notice {
+                        account: account.clone(),
+                        conversation: channel_jid.clone(),
+                        text: text,
+                    });
+                }
+            },
+            Event::Presence(account, presence) => {
                 if let Some(Jid::Full(from)) = &presence.from {
-                    let channel_jid: BareJid = from.clone().into();
-                    if let Some(conversation::Conversation::Channel(channel)) = self.conversations.get_mut(&channel_jid.to_string()) {
-                        for payload in presence.clone().payloads {
-                            if let Some(muc_user) = muc::user::MucUser::try_from(payload).ok() {
-                                for item in muc_user.items {
-                                    let occupant_jid = match item.jid {
-                                        Some(full) => Some(full.into()),
-                                        None => None,
-                                    };
-                                    let occupant = conversation::Occupant {
-                                        nick: from.resource.clone(),
-                                        jid: occupant_jid,
-                                        affiliation: item.affiliation.into(),
-                                        role: item.role.into(),
-                                    };
-                                    Rc::clone(&aparte).event(Event::Occupant(occupant.clone()));
-                                    channel.occupants.insert(occupant.nick.clone(), occupant);
-                                }
-                            }
-                        }
+                    if presence.type_ == PresenceType::Error {
+                        self.handle_join_error(Rc::clone(&aparte), account, from, presence);
+                        return;
+                    }
+
+                    for event in self.handle_occupant_presence(account, from, presence) {
+                        Rc::clone(&aparte).event(event);
+                    }
+
+                    if Self::room_created(presence) {
+                        let channel: BareJid = from.clone().into();
+                        Rc::clone(&aparte).event(Event::Notice {
+                            account: account.clone(),
+                            conversation: channel.clone(),
+                            text: format!("Room created, it stays locked until its configuration is submitted with /roomconfig submit"),
+                        });
+                        Self::room_config(aparte, account, &channel);
                     }
                 }
             },
@@ -101,3 +511,117 @@ impl fmt::Display for ConversationPlugin {
         write!(f, "Conversations management")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use xmpp_parsers::Element;
+
+    fn presence(xml: &str) -> Presence {
+        Presence::try_from(Element::from_str(xml).unwrap()).unwrap()
+    }
+
+    fn notices(events: &[Event]) -> Vec<String> {
+        events.iter().filter_map(|event| match event {
+            Event::Notice { text, .. } => Some(text.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    fn joined_channel() -> (ConversationPlugin, FullJid) {
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let mut plugin = ConversationPlugin::new();
+        plugin.conversations.insert((account.clone().into(), BareJid::from_str("room@conference.server.tld").unwrap()), conversation::Conversation::Channel(conversation::Channel {
+            account: account.clone(),
+            jid: BareJid::from_str("room@conference.server.tld").unwrap(),
+            nick: "me".to_string(),
+            name: None,
+            occupants: HashMap::new(),
+            subject: None,
+            password: None,
+            joined: false,
+        }));
+
+        for xml in &[
+            "<presence xmlns='jabber:client' from='room@conference.server.tld/other'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant'/></x></presence>",
+            "<presence xmlns='jabber:client' from='room@conference.server.tld/me'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='member' role='participant'/><status code='110'/></x></presence>",
+        ] {
+            let presence = presence(xml);
+            let from = match &presence.from {
+                Some(Jid::Full(from)) => from.clone(),
+                _ => unreachable!(),
+            };
+            plugin.handle_occupant_presence(&account, &from, &presence);
+        }
+
+        (plugin, account)
+    }
+
+    fn occupants(plugin: &ConversationPlugin, account: &FullJid) -> Vec<String> {
+        match plugin.channel(account, &BareJid::from_str("room@conference.server.tld").unwrap()) {
+            Some(channel) => {
+                let mut nicks = channel.occupants.keys().cloned().collect::<Vec<_>>();
+                nicks.sort();
+                nicks
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_nick_change_is_not_a_join() {
+        let (mut plugin, account) = joined_channel();
+        let from = FullJid::from_str("room@conference.server.tld/other").unwrap();
+
+        let events = plugin.handle_occupant_presence(&account, &from, &presence("<presence xmlns='jabber:client' from='room@conference.server.tld/other' type='unavailable'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant' nick='renamed'/><status code='303'/></x></presence>"));
+        assert_eq!(notices(&events), vec!["other is now known as renamed".to_string()]);
+
+        let from = FullJid::from_str("room@conference.server.tld/renamed").unwrap();
+        let events = plugin.handle_occupant_presence(&account, &from, &presence("<presence xmlns='jabber:client' from='room@conference.server.tld/renamed'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant'/></x></presence>"));
+        assert!(notices(&events).is_empty());
+        assert_eq!(occupants(&plugin, &account), vec!["me".to_string(), "renamed".to_string()]);
+    }
+
+    #[test]
+    fn test_kicked_occupant_is_removed() {
+        let (mut plugin, account) = joined_channel();
+        let from = FullJid::from_str("room@conference.server.tld/other").unwrap();
+
+        let events = plugin.handle_occupant_presence(&account, &from, &presence("<presence xmlns='jabber:client' from='room@conference.server.tld/other' type='unavailable'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='none'><reason>Spam</reason></item><status code='307'/></x></presence>"));
+        assert_eq!(notices(&events), vec!["other has been kicked (Spam)".to_string()]);
+        assert_eq!(occupants(&plugin, &account), vec!["me".to_string()]);
+    }
+
+    #[test]
+    fn test_presence_on_another_account_is_ignored() {
+        let (mut plugin, account) = joined_channel();
+        let other = FullJid::from_str("other@server.tld/aparte").unwrap();
+        let from = FullJid::from_str("room@conference.server.tld/other").unwrap();
+
+        let events = plugin.handle_occupant_presence(&other, &from, &presence("<presence xmlns='jabber:client' from='room@conference.server.tld/other' type='unavailable'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant'/></x></presence>"));
+        assert!(events.is_empty());
+        assert!(plugin.channel(&other, &BareJid::from_str("room@conference.server.tld").unwrap()).is_none());
+        assert_eq!(occupants(&plugin, &account), vec!["me".to_string(), "other".to_string()]);
+    }
+
+    #[test]
+    fn test_occupants_are_forgotten_on_rejoin() {
+        let (mut plugin, account) = joined_channel();
+
+        let events = plugin.forget_occupants(&account);
+        assert_eq!(events.iter().filter(|event| match event {
+            Event::OccupantRemoved { .. } => true,
+            _ => false,
+        }).count(), 2);
+        assert!(occupants(&plugin, &account).is_empty());
+        assert!(!plugin.channel(&account, &BareJid::from_str("room@conference.server.tld").unwrap()).unwrap().joined);
+    }
+
+    #[test]
+    fn test_alternate_nick() {
+        assert_eq!(ConversationPlugin::alternate_nick("me"), Some("me_".to_string()));
+        assert_eq!(ConversationPlugin::alternate_nick("me__"), Some("me___".to_string()));
+        assert_eq!(ConversationPlugin::alternate_nick("me___"), None);
+    }
+}
@@ -1,32 +1,214 @@
+use futures::{future, Future};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
 use std::rc::Rc;
+use uuid::Uuid;
+use xmpp_parsers::caps::{self, Caps};
+use xmpp_parsers::disco::{DiscoInfoQuery, DiscoInfoResult, DiscoItemsQuery, DiscoItemsResult, Feature, Identity};
+use xmpp_parsers::hashes::Algo;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::presence::Type as PresenceType;
+use xmpp_parsers::stanza_error::{DefinedCondition, ErrorType, StanzaError};
+use xmpp_parsers::{BareJid, FullJid, Jid, ns};
 
-use crate::core::{Plugin, Aparte, Event};
+use crate::core::{Plugin, Aparte, Event, IqError};
+
+const CAPS_NODE: &str = "https://github.com/paulfariello/aparte";
 
 #[allow(non_camel_case_types)]
 pub struct Disco<'a> {
     features: Vec<&'a str>,
+    caps: Caps,
+    /// disco#info results of entities queried directly, for each account
+    infos: HashMap<(BareJid, String), DiscoInfoResult>,
+    /// Verified disco#info results, shared by every entity announcing the same caps to an account
+    caps_infos: HashMap<(BareJid, String), DiscoInfoResult>,
+    /// Caps announced in the last presence each account received from an entity
+    entity_caps: HashMap<(BareJid, String), Caps>,
 }
 
 impl<'a> Disco<'a> {
     pub fn add_feature(&mut self, feature: &'a str) -> Result<(), ()> {
         debug!("Adding `{}` feature", feature);
         self.features.push(feature);
+        self.caps = Self::compute_caps(&self.features);
 
         Ok(())
     }
+
+    fn disco_info(features: &[&str], node: Option<String>) -> DiscoInfoResult {
+        DiscoInfoResult {
+            node: node,
+            identities: vec![Identity::new("client", "console", "en", "Aparté")],
+            features: features.iter().map(|feature| Feature::new(*feature)).collect(),
+            extensions: Vec::new(),
+        }
+    }
+
+    fn compute_caps(features: &[&str]) -> Caps {
+        let info = Self::disco_info(features, None);
+        let hash = caps::hash_caps(&caps::compute_disco(&info), Algo::Sha_1).unwrap();
+        Caps::new(CAPS_NODE, hash)
+    }
+
+    /// XEP-0115 entity capabilities to attach to our presences
+    pub fn caps(&self) -> &Caps {
+        &self.caps
+    }
+
+    fn caps_node(&self) -> String {
+        caps_node(&self.caps)
+    }
+
+    /// Cached disco#info of an entity, as answered to an account
+    pub fn cached_info(&self, account: &FullJid, jid: &Jid) -> Option<&DiscoInfoResult> {
+        let account: BareJid = account.clone().into();
+        self.entity_caps.get(&(account.clone(), jid.to_string()))
+            .and_then(|caps| self.caps_infos.get(&(account.clone(), caps_node(caps))))
+            .or_else(|| self.infos.get(&(account, jid.to_string())))
+    }
+
+    /// disco#info of an entity, from the cache when possible
+    pub fn info(aparte: Rc<Aparte>, account: &FullJid, jid: Jid) -> Box<dyn Future<Item = DiscoInfoResult, Error = IqError>> {
+        let cached = {
+            let disco = aparte.get_plugin::<Disco>().unwrap();
+            disco.cached_info(account, &jid).cloned()
+        };
+
+        match cached {
+            Some(info) => Box::new(future::ok(info)),
+            None => Box::new(Self::query_info(aparte, account, jid, None)),
+        }
+    }
+
+    /// Resolve to whether an entity supports a feature, querying it when not cached
+    pub fn supports(aparte: Rc<Aparte>, account: &FullJid, jid: Jid, feature: &'static str) -> impl Future<Item = bool, Error = IqError> {
+        Self::info(aparte, account, jid).map(move |info| info.features.iter().any(|f| f.var == feature))
+    }
+
+    /// Query disco#info of an entity and refresh the cache with the answer
+    pub fn query_info(aparte: Rc<Aparte>, account: &FullJid, jid: Jid, node: Option<String>) -> impl Future<Item = DiscoInfoResult, Error = IqError> {
+        let bare: BareJid = account.clone().into();
+        let caps = match node {
+            Some(_) => None,
+            None => aparte.get_plugin::<Disco>().unwrap().entity_caps.get(&(bare.clone(), jid.to_string())).cloned(),
+        };
+
+        let query = DiscoInfoQuery { node: node.clone().or_else(|| caps.as_ref().map(caps_node)) };
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Iq::from_get(id, query).with_to(jid.clone());
+
+        let cache_aparte = Rc::clone(&aparte);
+        aparte.iq(account, iq).and_then(move |payload| {
+            let info = match payload.map(DiscoInfoResult::try_from) {
+                Some(Ok(info)) => info,
+                _ => return Err(IqError::Invalid),
+            };
+
+            if node.is_none() {
+                let mut disco = cache_aparte.get_plugin_mut::<Disco>().unwrap();
+                match caps {
+                    Some(caps) if verify_caps(&caps, &info) => {
+                        disco.caps_infos.insert((bare, caps_node(&caps)), info.clone());
+                    },
+                    _ => {
+                        disco.infos.insert((bare, jid.to_string()), info.clone());
+                    },
+                }
+            }
+
+            Ok(info)
+        })
+    }
+
+    pub fn query_items(aparte: Rc<Aparte>, account: &FullJid, jid: Jid, node: Option<String>) -> impl Future<Item = DiscoItemsResult, Error = IqError> {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Iq::from_get(id, DiscoItemsQuery { node: node }).with_to(jid);
+
+        aparte.iq(account, iq).and_then(|payload| {
+            match payload.map(DiscoItemsResult::try_from) {
+                Some(Ok(items)) => Ok(items),
+                _ => Err(IqError::Invalid),
+            }
+        })
+    }
+}
+
+fn caps_node(caps: &Caps) -> String {
+    format!("{}#{}", caps.node, caps.hash.to_base64())
+}
+
+/// Check that a disco#info result matches the caps hash it was fetched for
+fn verify_caps(caps: &Caps, info: &DiscoInfoResult) -> bool {
+    match caps::hash_caps(&caps::compute_disco(info), caps.hash.algo.clone()) {
+        Ok(hash) => hash == caps.hash,
+        Err(_) => false,
+    }
 }
 
 impl<'a> Plugin for Disco<'a> {
     fn new() -> Disco<'a> {
-        Disco { features: Vec::new() }
+        let features = vec![ns::DISCO_INFO, ns::CAPS];
+        let caps = Self::compute_caps(&features);
+
+        Disco {
+            features: features,
+            caps: caps,
+            infos: HashMap::new(),
+            caps_infos: HashMap::new(),
+            entity_caps: HashMap::new(),
+        }
     }
 
     fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
         Ok(())
     }
 
-    fn on_event(&mut self, _aparte: Rc<Aparte>, _event: &Event) {
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Iq(account, iq) => {
+                if let IqType::Get(payload) = &iq.payload {
+                    if let Ok(query) = DiscoInfoQuery::try_from(payload.clone()) {
+                        let known = match &query.node {
+                            Some(node) => node == &self.caps_node(),
+                            None => true,
+                        };
+
+                        let mut response = match known {
+                            true => Iq::from_result(iq.id.clone(), Some(Self::disco_info(&self.features, query.node))),
+                            false => {
+                                let error = StanzaError::new(ErrorType::Cancel, DefinedCondition::ItemNotFound, "en", "Unknown node");
+                                Iq::from_error(iq.id.clone(), error)
+                            },
+                        };
+                        response.to = iq.from.clone();
+
+                        aparte.send(account, response.into());
+                    }
+                }
+            },
+            Event::Presence(account, presence) => {
+                if let Some(from) = &presence.from {
+                    let key = (account.clone().into(), from.to_string());
+                    match presence.type_ {
+                        PresenceType::None => {
+                            let caps = presence.payloads.iter().find_map(|payload| Caps::try_from(payload.clone()).ok());
+                            match caps {
+                                Some(caps) => self.entity_caps.insert(key, caps),
+                                None => self.entity_caps.remove(&key),
+                            };
+                        },
+                        PresenceType::Unavailable => {
+                            self.entity_caps.remove(&key);
+                            self.infos.remove(&key);
+                        },
+                        _ => {},
+                    }
+                }
+            },
+            _ => {},
+        }
     }
 }
 
@@ -0,0 +1,372 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use futures::Future;
+use futures::sync::oneshot;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::thread;
+use xmpp_parsers::{BareJid, FullJid, Jid};
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::message::{ChatMessage, GroupchatMessage, Message, XmppMessage};
+
+/// Number of messages shown when opening a window, and then on each page up
+const PAGE_SIZE: usize = 50;
+/// Maximum number of messages printed by a search
+const MAX_RESULTS: usize = 100;
+/// History files hold one JSON record per line, new messages are appended
+const EXTENSION: &str = "jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Chat,
+    Groupchat,
+}
+
+/// A message as stored on disk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Record {
+    id: String,
+    timestamp: String,
+    direction: Direction,
+    kind: Kind,
+    from: String,
+    to: String,
+    body: String,
+}
+
+impl Record {
+    /// Record of a message along with the conversation it belongs to
+    fn from_message(message: &Message) -> Option<(BareJid, Record)> {
+        let (direction, kind, conversation, id, timestamp, from, to, body) = match message {
+            Message::Incoming(XmppMessage::Chat(ChatMessage { id, timestamp, from, from_full, to_full, body, .. })) =>
+                (Direction::Incoming, Kind::Chat, from, id, timestamp, from_full, to_full, body),
+            Message::Outgoing(XmppMessage::Chat(ChatMessage { id, timestamp, to, from_full, to_full, body, .. })) =>
+                (Direction::Outgoing, Kind::Chat, to, id, timestamp, from_full, to_full, body),
+            Message::Incoming(XmppMessage::Groupchat(GroupchatMessage { id, timestamp, from, from_full, to_full, body, .. })) =>
+                (Direction::Incoming, Kind::Groupchat, from, id, timestamp, from_full, to_full, body),
+            Message::Outgoing(XmppMessage::Groupchat(GroupchatMessage { id, timestamp, to, from_full, to_full, body, .. })) =>
+                (Direction::Outgoing, Kind::Groupchat, to, id, timestamp, from_full, to_full, body),
+            Message::Log(_) => return None,
+        };
+
+        Some((conversation.clone(), Record {
+            id: id.clone(),
+            timestamp: timestamp.to_rfc3339(),
+            direction: direction,
+            kind: kind,
+            from: from.to_string(),
+            to: to.to_string(),
+            body: body.clone(),
+        }))
+    }
+
+    fn message(&self) -> Option<Message> {
+        let timestamp = DateTime::parse_from_rfc3339(&self.timestamp).ok()?.with_timezone(&Utc);
+        let from = Jid::from_str(&self.from).ok()?;
+        let to = Jid::from_str(&self.to).ok()?;
+        let mut message = match (self.direction, self.kind) {
+            (Direction::Incoming, Kind::Chat) => Message::incoming_chat(self.id.clone(), timestamp, &from, &to, &self.body),
+            (Direction::Outgoing, Kind::Chat) => Message::outgoing_chat(self.id.clone(), timestamp, &from, &to, &self.body),
+            (Direction::Incoming, Kind::Groupchat) => Message::incoming_groupchat(self.id.clone(), timestamp, &from, &to, &self.body),
+            (Direction::Outgoing, Kind::Groupchat) => Message::outgoing_groupchat(self.id.clone(), timestamp, &from, &to, &self.body),
+        };
+        // It was sent in a previous session, the server got it at some point
+        message.ack();
+        Some(message)
+    }
+}
+
+/// Messages of a conversation stored on disk, parsed once
+#[derive(Debug, Default)]
+struct Stored {
+    /// Oldest first
+    messages: Vec<Message>,
+    ids: HashSet<String>,
+}
+
+/// Messages already sent to the window of a conversation, from any source
+#[derive(Debug, Default)]
+struct Shown {
+    oldest: Option<DateTime<Utc>>,
+    ids: HashSet<String>,
+    /// Whether the archive was asked for the messages newer than the stored ones
+    caught_up: bool,
+}
+
+/// Page of stored messages preceding the oldest one shown, in chronological order
+fn page(messages: &[Message], shown: &Shown) -> Vec<Message> {
+    let mut page: Vec<Message> = messages.iter().rev().filter(|message| {
+        shown.oldest.map_or(true, |oldest| message.timestamp() <= &oldest) && !shown.ids.contains(message.id())
+    }).take(PAGE_SIZE).cloned().collect();
+    page.reverse();
+    page
+}
+
+/// Records of a history file, one JSON object per line
+fn read(path: &Path) -> Vec<Record> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+
+    BufReader::new(file).lines().map_while(Result::ok).filter(|line| !line.is_empty()).filter_map(|line| {
+        match serde_json::from_str(&line) {
+            Ok(record) => Some(record),
+            Err(err) => {
+                warn!("Invalid history line in {}: {}", path.display(), err);
+                None
+            },
+        }
+    }).collect()
+}
+
+/// Stored messages containing a text, in any conversation of any account, oldest first
+fn find(dir: &Path, text: &str) -> Vec<(BareJid, Message)> {
+    let text = text.to_lowercase();
+    let mut results = Vec::new();
+
+    let accounts = match fs::read_dir(dir) {
+        Ok(accounts) => accounts,
+        Err(_) => return results,
+    };
+    for account in accounts.filter_map(Result::ok) {
+        let conversations = match fs::read_dir(account.path()) {
+            Ok(conversations) => conversations,
+            Err(_) => continue,
+        };
+        for conversation in conversations.filter_map(Result::ok) {
+            let path = conversation.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let jid = match path.file_stem().and_then(|stem| stem.to_str()).map(BareJid::from_str) {
+                Some(Ok(jid)) => jid,
+                _ => continue,
+            };
+
+            for record in read(&path).into_iter().filter(|record| record.body.to_lowercase().contains(&text)) {
+                if let Some(message) = record.message() {
+                    results.push((jid.clone(), message));
+                }
+            }
+        }
+    }
+
+    results.sort_by(|(_, a), (_, b)| a.cmp(b));
+    let skip = results.len().saturating_sub(MAX_RESULTS);
+    results.drain(..skip);
+    results
+}
+
+pub struct HistoryPlugin {
+    dir: PathBuf,
+    /// Stored messages of each account and conversation, loaded when first needed
+    stored: HashMap<(BareJid, BareJid), Stored>,
+    shown: HashMap<(BareJid, BareJid), Shown>,
+}
+
+impl HistoryPlugin {
+    fn path(&self, account: &BareJid, conversation: &BareJid) -> PathBuf {
+        self.dir.join(account.to_string()).join(format!("{}.{}", conversation, EXTENSION))
+    }
+
+    fn stored(&mut self, account: &BareJid, conversation: &BareJid) -> &mut Stored {
+        let path = self.path(account, conversation);
+        self.stored.entry((account.clone(), conversation.clone())).or_insert_with(|| {
+            let mut messages: Vec<Message> = read(&path).iter().filter_map(Record::message).collect();
+            messages.sort();
+            let ids = messages.iter().map(|message| message.id().to_string()).collect();
+            Stored { messages, ids }
+        })
+    }
+
+    fn record(&mut self, account: &FullJid, message: &Message) {
+        let (conversation, record) = match Record::from_message(message) {
+            Some(record) => record,
+            None => return,
+        };
+        let account: BareJid = account.clone().into();
+
+        let shown = self.shown.entry((account.clone(), conversation.clone())).or_default();
+        if shown.oldest.map_or(true, |oldest| message.timestamp() < &oldest) {
+            shown.oldest = Some(*message.timestamp());
+        }
+        shown.ids.insert(record.id.clone());
+
+        let path = self.path(&account, &conversation);
+        let stored = self.stored(&account, &conversation);
+        if !stored.ids.insert(record.id.clone()) {
+            return;
+        }
+        let position = stored.messages.iter().rposition(|existing| existing <= message).map_or(0, |position| position + 1);
+        stored.messages.insert(position, message.clone());
+
+        let result = serde_json::to_string(&record).map_err(|err| err.to_string()).and_then(|line| {
+            fs::create_dir_all(path.parent().unwrap()).map_err(|err| err.to_string())?;
+            let mut file = OpenOptions::new().create(true).append(true).open(&path).map_err(|err| err.to_string())?;
+            writeln!(file, "{}", line).map_err(|err| err.to_string())
+        });
+        if let Err(err) = result {
+            warn!("Cannot save history {}: {}", path.display(), err);
+        }
+    }
+
+    /// Send the next older page of stored messages to the window of a conversation, or ask the
+    /// archive for the messages preceding the oldest one shown once none is left. When the window
+    /// is opened, the archive is also asked for the messages newer than the stored ones.
+    fn load_history(&mut self, aparte: Rc<Aparte>, account: &FullJid, conversation: &BareJid, groupchat: bool) {
+        let bare: BareJid = account.clone().into();
+        let key = (bare.clone(), conversation.clone());
+        self.stored(&bare, conversation);
+        let newest = self.stored[&key].messages.last().map(|message| *message.timestamp());
+        let shown = self.shown.entry(key.clone()).or_default();
+        let messages = page(&self.stored[&key].messages, shown);
+
+        if !shown.caught_up {
+            shown.caught_up = true;
+            // Without stored messages, the page preceding the oldest one shown is the latest one
+            if let Some(start) = newest {
+                Rc::clone(&aparte).event(Event::CatchUpArchive {
+                    account: account.clone(),
+                    conversation: conversation.clone(),
+                    groupchat: groupchat,
+                    start: start,
+                });
+            }
+        }
+
+        if messages.is_empty() {
+            Rc::clone(&aparte).event(Event::LoadArchive {
+                account: account.clone(),
+                conversation: conversation.clone(),
+                groupchat: groupchat,
+                end: self.shown[&key].oldest,
+            });
+        }
+        for message in messages {
+            Rc::clone(&aparte).event(Event::Message(Some(account.clone()), message));
+        }
+    }
+
+    /// Show archived messages which aren't already
+    fn archived(&mut self, aparte: Rc<Aparte>, account: &FullJid, conversation: &BareJid, messages: &[Message]) {
+        let shown = self.shown.entry((account.clone().into(), conversation.clone())).or_default();
+        for message in messages.iter().filter(|message| !shown.ids.contains(message.id())) {
+            Rc::clone(&aparte).event(Event::Message(Some(account.clone()), message.clone()));
+        }
+    }
+
+    /// Stored messages containing a text, searched in a thread not to block the event loop while
+    /// every history file is read
+    pub fn search(&self, text: &str) -> impl Future<Item = Vec<(BareJid, Message)>, Error = String> {
+        let (sender, receiver) = oneshot::channel();
+        let dir = self.dir.clone();
+        let text = text.to_string();
+        thread::spawn(move || {
+            let _ = sender.send(find(&dir, &text));
+        });
+
+        receiver.map_err(|_| "Search was interrupted".to_string())
+    }
+}
+
+impl Plugin for HistoryPlugin {
+    fn new() -> HistoryPlugin {
+        Self {
+            dir: PathBuf::new(),
+            stored: HashMap::new(),
+            shown: HashMap::new(),
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        self.dir = aparte.data_dir.join("history");
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Message(Some(account), message) => self.record(account, message),
+            Event::LoadHistory { account, conversation, groupchat } => self.load_history(aparte, account, conversation, *groupchat),
+            Event::ArchivedMessages { account, conversation, messages } => self.archived(aparte, account, conversation, messages),
+            Event::ConversationClosed { account, conversation } => {
+                // The window will be filled again when reopened
+                self.shown.remove(&(account.clone().into(), conversation.clone()));
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for HistoryPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Message history")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, timestamp: &str) -> Message {
+        let timestamp = DateTime::parse_from_rfc3339(timestamp).unwrap().with_timezone(&Utc);
+        Message::incoming_chat(id, timestamp, &Jid::from_str("contact@server.tld/phone").unwrap(), &Jid::from_str("me@server.tld/aparte").unwrap(), "Hi")
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let message = Message::outgoing_groupchat("id", Utc::now(), &Jid::from_str("me@server.tld/aparte").unwrap(), &Jid::from_str("room@conference.server.tld").unwrap(), "Hello\n\"world\"");
+        let (conversation, record) = Record::from_message(&message).unwrap();
+        assert_eq!(conversation, BareJid::from_str("room@conference.server.tld").unwrap());
+
+        let line = serde_json::to_string(&record).unwrap();
+        assert!(!line.contains('\n'));
+        assert_eq!(serde_json::from_str::<Record>(&line).unwrap(), record);
+
+        let restored = record.message().unwrap();
+        assert_eq!(restored, message);
+        assert_eq!(restored.body(), message.body());
+        assert_eq!(restored.timestamp().timestamp(), message.timestamp().timestamp());
+    }
+
+    #[test]
+    fn test_pages_go_back_in_time() {
+        let messages: Vec<Message> = (0..PAGE_SIZE + 10).map(|i| {
+            message(&i.to_string(), &format!("2020-01-01T10:{:02}:{:02}+00:00", i / 60, i % 60))
+        }).collect();
+
+        // A live message was received before the window was filled
+        let mut shown = Shown::default();
+        let live = message("live", "2020-01-01T11:00:00+00:00");
+        shown.oldest = Some(*live.timestamp());
+        shown.ids.insert(live.id().to_string());
+
+        let last = page(&messages, &shown);
+        assert_eq!(last.len(), PAGE_SIZE);
+        assert_eq!(last.first().unwrap().id(), "10");
+        assert_eq!(last.last().unwrap().id(), (PAGE_SIZE + 9).to_string());
+
+        shown.oldest = Some(*last[0].timestamp());
+        shown.ids.extend(last.iter().map(|message| message.id().to_string()));
+        let previous = page(&messages, &shown);
+        assert_eq!(previous.iter().map(|message| message.id().to_string()).collect::<Vec<_>>(), (0..10).map(|i| i.to_string()).collect::<Vec<_>>());
+
+        shown.oldest = Some(*previous[0].timestamp());
+        shown.ids.extend(previous.iter().map(|message| message.id().to_string()));
+        assert!(page(&messages, &shown).is_empty());
+    }
+}
@@ -0,0 +1,381 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::Future;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, FullJid, Jid, ns};
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+use xmpp_parsers::iq::Iq;
+use xmpp_parsers::mam::{Complete, Fin, Query, QueryId, Result_};
+use xmpp_parsers::message::MessageType;
+use xmpp_parsers::rsm::SetQuery;
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::message::{self, Message};
+
+/// Number of archived messages fetched at once
+const PAGE_SIZE: usize = 50;
+
+/// Paging state of the archive of a conversation
+#[derive(Debug, Default)]
+struct Archive {
+    /// Archive id of the oldest message fetched, next page ends before it
+    first: Option<String>,
+    complete: bool,
+    pending: bool,
+}
+
+/// Query waiting for its results
+struct PendingQuery {
+    account: FullJid,
+    conversation: BareJid,
+    /// Archive queried, None for the one of our account
+    archive: Option<BareJid>,
+    /// Start of a catch up query, which pages forward without moving the paging state
+    start: Option<DateTime<Utc>>,
+    messages: Vec<Message>,
+}
+
+impl PendingQuery {
+    fn new(account: &FullJid, conversation: &BareJid, groupchat: bool, start: Option<DateTime<Utc>>) -> PendingQuery {
+        PendingQuery {
+            account: account.clone(),
+            conversation: conversation.clone(),
+            // Channels keep their own archive, our account's one holds private conversations
+            archive: match groupchat {
+                true => Some(conversation.clone()),
+                false => None,
+            },
+            start: start,
+            messages: Vec::new(),
+        }
+    }
+}
+
+pub struct MamPlugin {
+    archives: HashMap<(BareJid, BareJid), Archive>,
+    queries: HashMap<String, PendingQuery>,
+}
+
+impl MamPlugin {
+    fn field(var: &str, type_: FieldType, value: String) -> Field {
+        Field {
+            var: String::from(var),
+            type_: type_,
+            label: None,
+            required: false,
+            options: Vec::new(),
+            values: vec![value],
+            media: Vec::new(),
+        }
+    }
+
+    /// Page preceding an archive id
+    fn before(before: Option<String>) -> SetQuery {
+        SetQuery {
+            max: Some(PAGE_SIZE),
+            after: None,
+            // An empty before asks for the last page
+            before: Some(before.unwrap_or_default()),
+            index: None,
+        }
+    }
+
+    /// Page following an archive id, or the first page
+    fn after(after: Option<String>) -> SetQuery {
+        SetQuery {
+            max: Some(PAGE_SIZE),
+            after: after,
+            before: None,
+            index: None,
+        }
+    }
+
+    fn query(queryid: &str, with: Option<&BareJid>, start: Option<&DateTime<Utc>>, end: Option<&DateTime<Utc>>, set: SetQuery) -> Query {
+        let mut fields = Vec::new();
+        if let Some(with) = with {
+            fields.push(Self::field("with", FieldType::JidSingle, with.to_string()));
+        }
+        if let Some(start) = start {
+            fields.push(Self::field("start", FieldType::TextSingle, start.to_rfc3339_opts(SecondsFormat::Millis, true)));
+        }
+        if let Some(end) = end {
+            fields.push(Self::field("end", FieldType::TextSingle, end.to_rfc3339_opts(SecondsFormat::Millis, true)));
+        }
+        let form = match fields.is_empty() {
+            true => None,
+            false => Some(DataForm {
+                type_: DataFormType::Submit,
+                form_type: Some(ns::MAM.to_string()),
+                title: None,
+                instructions: None,
+                fields: fields,
+            }),
+        };
+
+        Query {
+            queryid: Some(QueryId(queryid.to_string())),
+            node: None,
+            form: form,
+            set: Some(set),
+        }
+    }
+
+    /// Message displayable in the conversation window, None for archived stanzas without body
+    fn archived_message(account: &FullJid, result: &Result_) -> Option<Message> {
+        let original = result.forwarded.stanza.as_ref()?;
+        let body = original.bodies.get("")?;
+        let from = original.from.as_ref()?;
+        let to = original.to.clone().unwrap_or_else(|| Jid::Full(account.clone()));
+        let id = original.id.clone().unwrap_or_else(|| result.id.clone());
+        let timestamp = result.forwarded.delay.as_ref().and_then(message::delay_timestamp).unwrap_or_else(Utc::now);
+
+        let bare: BareJid = account.clone().into();
+        match original.type_ {
+            MessageType::Groupchat => Some(Message::incoming_groupchat(id, timestamp, from, &to, &body.0)),
+            MessageType::Chat | MessageType::Normal => match BareJid::from(from.clone()) == bare {
+                true => Some(Message::outgoing_chat(id, timestamp, from, &to, &body.0)),
+                false => Some(Message::incoming_chat(id, timestamp, from, &to, &body.0)),
+            },
+            _ => None,
+        }
+    }
+
+    /// Ask for the page preceding the messages already fetched, and the oldest one shown
+    fn load(&mut self, aparte: Rc<Aparte>, account: &FullJid, conversation: &BareJid, groupchat: bool, end: Option<&DateTime<Utc>>) {
+        let archive = self.archives.entry((account.clone().into(), conversation.clone())).or_default();
+        if archive.pending || archive.complete {
+            return;
+        }
+        archive.pending = true;
+
+        let set = Self::before(archive.first.clone());
+        self.send(aparte, PendingQuery::new(account, conversation, groupchat, None), end, set);
+    }
+
+    /// Ask for the messages following the newest one stored, page after page up to the most recent
+    fn catch_up(&mut self, aparte: Rc<Aparte>, account: &FullJid, conversation: &BareJid, groupchat: bool, start: &DateTime<Utc>, after: Option<String>) {
+        self.send(aparte, PendingQuery::new(account, conversation, groupchat, Some(*start)), None, Self::after(after));
+    }
+
+    fn send(&mut self, aparte: Rc<Aparte>, pending: PendingQuery, end: Option<&DateTime<Utc>>, set: SetQuery) {
+        let queryid = Uuid::new_v4().to_hyphenated().to_string();
+        let with = match pending.archive {
+            Some(_) => None,
+            None => Some(&pending.conversation),
+        };
+        let mut iq = Iq::from_set(Uuid::new_v4().to_hyphenated().to_string(), Self::query(&queryid, with, pending.start.as_ref(), end, set));
+        iq.to = pending.archive.clone().map(Jid::Bare);
+        let account = pending.account.clone();
+        self.queries.insert(queryid.clone(), pending);
+
+        let fin_aparte = Rc::clone(&aparte);
+        let fin_queryid = queryid.clone();
+        let error_aparte = Rc::clone(&aparte);
+        tokio::runtime::current_thread::spawn(Rc::clone(&aparte).iq(&account, iq).map(move |payload| {
+            match payload.map(Fin::try_from) {
+                Some(Ok(fin)) => Self::handle_fin(fin_aparte, &fin_queryid, Some(fin)),
+                Some(Err(err)) => {
+                    warn!("Invalid archive result: {}", err);
+                    Self::handle_fin(fin_aparte, &fin_queryid, None);
+                },
+                None => Self::handle_fin(fin_aparte, &fin_queryid, None),
+            }
+        }).map_err(move |err| {
+            warn!("Cannot query archive: {}", err);
+            Self::handle_fin(error_aparte, &queryid, None);
+        }));
+    }
+
+    /// Whether the archive has no older page, pages may hold only messages without body so only the
+    /// server can tell
+    fn is_complete(fin: &Fin) -> bool {
+        fin.complete == Complete::True || fin.set.first.is_none() || fin.set.count == Some(0)
+    }
+
+    /// Hand the results of a finished query to the history, oldest first
+    fn handle_fin(aparte: Rc<Aparte>, queryid: &str, fin: Option<Fin>) {
+        let (query, next) = {
+            let mut plugin = aparte.get_plugin_mut::<MamPlugin>().unwrap();
+            let query = match plugin.queries.remove(queryid) {
+                Some(query) => query,
+                None => return,
+            };
+
+            let next = match query.start {
+                // Catching up goes on with the following page, scrollback paging is left as is
+                Some(_) => match fin {
+                    Some(ref fin) if !Self::is_complete(fin) => fin.set.last.clone(),
+                    _ => None,
+                },
+                None => {
+                    let archive = plugin.archives.entry((query.account.clone().into(), query.conversation.clone())).or_default();
+                    archive.pending = false;
+                    match fin {
+                        Some(fin) => {
+                            archive.complete = Self::is_complete(&fin);
+                            if fin.set.first.is_some() {
+                                archive.first = fin.set.first;
+                            }
+                        },
+                        // Archive not supported or not allowed, don't ask again
+                        None => archive.complete = true,
+                    }
+                    None
+                },
+            };
+
+            (query, next)
+        };
+
+        let PendingQuery { account, conversation, archive, start, mut messages } = query;
+        messages.sort();
+        Rc::clone(&aparte).event(Event::ArchivedMessages {
+            account: account.clone(),
+            conversation: conversation.clone(),
+            messages: messages,
+        });
+
+        if let (Some(start), Some(after)) = (start, next) {
+            let mut plugin = aparte.get_plugin_mut::<MamPlugin>().unwrap();
+            plugin.catch_up(Rc::clone(&aparte), &account, &conversation, archive.is_some(), &start, Some(after));
+        }
+    }
+
+    fn handle_result(&mut self, account: &FullJid, from: &Option<Jid>, result: &Result_) {
+        let query = match result.queryid.as_ref().and_then(|queryid| self.queries.get_mut(&queryid.0)) {
+            Some(query) => query,
+            None => return,
+        };
+
+        // Results must come from the archive we queried
+        let bare: BareJid = account.clone().into();
+        let expected = query.archive.clone().unwrap_or(bare);
+        match from {
+            None if query.archive.is_none() => {},
+            Some(Jid::Bare(from)) if from == &expected => {},
+            _ => {
+                warn!("Ignoring archive result from {:?}", from);
+                return;
+            },
+        }
+
+        if &query.account != account {
+            return;
+        }
+
+        if let Some(message) = Self::archived_message(account, result) {
+            query.messages.push(message);
+        }
+    }
+}
+
+impl Plugin for MamPlugin {
+    fn new() -> MamPlugin {
+        Self {
+            archives: HashMap::new(),
+            queries: HashMap::new(),
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::LoadArchive { account, conversation, groupchat, end } => self.load(aparte, account, conversation, *groupchat, end.as_ref()),
+            Event::CatchUpArchive { account, conversation, groupchat, start } => self.catch_up(aparte, account, conversation, *groupchat, start, None),
+            Event::ConversationClosed { account, conversation } => {
+                // Paging starts over from the most recent messages when the window is reopened
+                self.archives.remove(&(account.clone().into(), conversation.clone()));
+            },
+            Event::Archive { account, from, result } => self.handle_result(account, from, result),
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for MamPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0313: Message Archive Management")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use xmpp_parsers::Element;
+
+    fn result(xml: &str) -> Result_ {
+        Result_::try_from(Element::from_str(xml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_archived_message_direction_and_timestamp() {
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+
+        let incoming = MamPlugin::archived_message(&account, &result("<result xmlns='urn:xmpp:mam:2' id='archive-1'><forwarded xmlns='urn:xmpp:forward:0'><delay xmlns='urn:xmpp:delay' stamp='2020-01-01T10:00:00Z'/><message xmlns='jabber:client' type='chat' from='contact@server.tld/phone' to='me@server.tld'><body>Hi</body></message></forwarded></result>")).unwrap();
+        match &incoming {
+            Message::Incoming(_) => {},
+            _ => panic!("Expected incoming message"),
+        }
+        assert_eq!(incoming.id(), "archive-1");
+        assert_eq!(incoming.timestamp().to_rfc3339(), "2020-01-01T10:00:00+00:00");
+
+        let outgoing = MamPlugin::archived_message(&account, &result("<result xmlns='urn:xmpp:mam:2' id='archive-2'><forwarded xmlns='urn:xmpp:forward:0'><delay xmlns='urn:xmpp:delay' stamp='2020-01-01T09:00:00Z'/><message xmlns='jabber:client' type='chat' id='sent' from='me@server.tld/aparte' to='contact@server.tld'><body>Hello</body></message></forwarded></result>")).unwrap();
+        match &outgoing {
+            Message::Outgoing(_) => {},
+            _ => panic!("Expected outgoing message"),
+        }
+        assert_eq!(outgoing.id(), "sent");
+        assert!(outgoing < incoming);
+    }
+
+    #[test]
+    fn test_archive_end_is_told_by_fin() {
+        let fin = |xml: &str| Fin::try_from(Element::from_str(xml).unwrap()).unwrap();
+
+        assert!(!MamPlugin::is_complete(&fin("<fin xmlns='urn:xmpp:mam:2'><set xmlns='http://jabber.org/protocol/rsm'><first>a</first><last>b</last><count>120</count></set></fin>")));
+        assert!(MamPlugin::is_complete(&fin("<fin xmlns='urn:xmpp:mam:2' complete='true'><set xmlns='http://jabber.org/protocol/rsm'><first>a</first><last>b</last></set></fin>")));
+        assert!(MamPlugin::is_complete(&fin("<fin xmlns='urn:xmpp:mam:2'><set xmlns='http://jabber.org/protocol/rsm'><count>0</count></set></fin>")));
+    }
+
+    #[test]
+    fn test_catch_up_pages_forward_from_start() {
+        let start = DateTime::parse_from_rfc3339("2020-01-01T10:00:00Z").unwrap().with_timezone(&Utc);
+        let query = MamPlugin::query("q", None, Some(&start), None, MamPlugin::after(Some("archive-1".to_string())));
+
+        let form = query.form.unwrap();
+        assert_eq!(form.fields.len(), 1);
+        assert_eq!(form.fields[0].var, "start");
+        assert_eq!(form.fields[0].values, vec!["2020-01-01T10:00:00.000Z".to_string()]);
+
+        let set = query.set.unwrap();
+        assert_eq!(set.after, Some("archive-1".to_string()));
+        assert_eq!(set.before, None);
+    }
+
+    #[test]
+    fn test_results_from_another_archive_are_ignored() {
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let mut plugin = MamPlugin::new();
+        plugin.queries.insert("q".to_string(), PendingQuery {
+            account: account.clone(),
+            conversation: BareJid::from_str("contact@server.tld").unwrap(),
+            archive: None,
+            start: None,
+            messages: Vec::new(),
+        });
+
+        let archived = result("<result xmlns='urn:xmpp:mam:2' id='archive-1' queryid='q'><forwarded xmlns='urn:xmpp:forward:0'><message xmlns='jabber:client' type='chat' from='contact@server.tld/phone' to='me@server.tld'><body>Hi</body></message></forwarded></result>");
+        plugin.handle_result(&account, &Some(Jid::from_str("evil@server.tld").unwrap()), &archived);
+        assert!(plugin.queries["q"].messages.is_empty());
+
+        plugin.handle_result(&account, &Some(Jid::from_str("me@server.tld").unwrap()), &archived);
+        assert_eq!(plugin.queries["q"].messages.len(), 1);
+    }
+}
@@ -2,4 +2,8 @@ pub mod disco;
 pub mod carbons;
 pub mod contact;
 pub mod conversation;
+pub mod presence;
+pub mod bookmarks;
+pub mod mam;
+pub mod history;
 pub mod ui;
@@ -0,0 +1,271 @@
+use chrono::{DateTime, Utc};
+use futures::{Future, Stream};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+use xmpp_parsers::FullJid;
+use xmpp_parsers::date;
+use xmpp_parsers::idle::Idle;
+use xmpp_parsers::presence::{Presence, Show, Type as PresenceType};
+
+use crate::config::AutoAway;
+use crate::core::{Plugin, Aparte, Event};
+
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Status as saved on disk, restored on next start
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedStatus {
+    show: String,
+    message: Option<String>,
+}
+
+pub struct PresencePlugin {
+    show: Option<Show>,
+    message: Option<String>,
+    path: PathBuf,
+    last_activity: DateTime<Utc>,
+    /// Show set automatically because of inactivity, overriding the chosen one
+    idle: Option<Show>,
+    idle_timer: bool,
+}
+
+impl PresencePlugin {
+    /// Parse the show part of a status, `available` being the absence of show
+    pub fn parse_show(show: &str) -> Result<Option<Show>, String> {
+        match show {
+            "available" => Ok(None),
+            "away" => Ok(Some(Show::Away)),
+            "chat" => Ok(Some(Show::Chat)),
+            "dnd" => Ok(Some(Show::Dnd)),
+            "xa" => Ok(Some(Show::Xa)),
+            _ => Err(format!("Unknown status {}", show)),
+        }
+    }
+
+    fn show_name(show: &Option<Show>) -> &'static str {
+        match show {
+            None => "available",
+            Some(Show::Away) => "away",
+            Some(Show::Chat) => "chat",
+            Some(Show::Dnd) => "dnd",
+            Some(Show::Xa) => "xa",
+        }
+    }
+
+    fn load(&mut self) {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(_) => return,
+        };
+
+        match toml::from_str::<SavedStatus>(&content) {
+            Ok(saved) => match Self::parse_show(&saved.show) {
+                Ok(show) => {
+                    self.show = show;
+                    self.message = saved.message;
+                },
+                Err(err) => warn!("Invalid saved status: {}", err),
+            },
+            Err(err) => warn!("Invalid saved status: {}", err),
+        }
+    }
+
+    fn save(&self) {
+        let saved = SavedStatus {
+            show: Self::show_name(&self.show).to_string(),
+            message: self.message.clone(),
+        };
+
+        let result = toml::to_string(&saved).map_err(|err| err.to_string())
+            .and_then(|content| fs::write(&self.path, content).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            warn!("Cannot save status: {}", err);
+        }
+    }
+
+    /// Our current presence for the given account, without recipient
+    pub fn presence(&self, aparte: &Aparte, account: &FullJid) -> Presence {
+        let mut presence = Presence::new(PresenceType::None);
+        let mut message = self.message.clone();
+
+        match &self.idle {
+            Some(show) => {
+                presence.show = Some(show.clone());
+                if let Some(idle_message) = aparte.config.auto_away.as_ref().and_then(|config| config.message.clone()) {
+                    message = Some(idle_message);
+                }
+                // XEP-0319: tell since when we have been idle
+                match date::DateTime::from_str(&self.last_activity.to_rfc3339()) {
+                    Ok(since) => presence.add_payload(Idle { since: since }),
+                    Err(err) => warn!("Invalid idle timestamp: {}", err),
+                }
+            },
+            None => presence.show = self.show.clone(),
+        }
+
+        if let Some(message) = message {
+            presence.set_status("", message);
+        }
+        presence.priority = aparte.account_config(account).and_then(|config| config.priority).unwrap_or(0);
+
+        presence
+    }
+
+    /// Show to use after being idle for some seconds, None while still considered active
+    fn idle_show(config: &AutoAway, show: &Option<Show>, idle: u64) -> Option<Show> {
+        // Never override a status deliberately set to away, xa or dnd
+        match show {
+            None | Some(Show::Chat) => {},
+            _ => return None,
+        }
+
+        match config.xa {
+            Some(xa) if idle >= xa => Some(Show::Xa),
+            _ if idle >= config.away => Some(Show::Away),
+            _ => None,
+        }
+    }
+
+    /// Record user activity, restoring our presence if we were automatically set away
+    pub fn key_pressed(aparte: Rc<Aparte>) {
+        let restore = {
+            let mut plugin = aparte.get_plugin_mut::<PresencePlugin>().unwrap();
+            plugin.last_activity = Utc::now();
+            plugin.idle.take().is_some()
+        };
+
+        if restore {
+            for account in aparte.accounts() {
+                Self::broadcast(&aparte, &account);
+            }
+        }
+    }
+
+    fn check_idle(aparte: Rc<Aparte>) {
+        let changed = match &aparte.config.auto_away {
+            Some(config) => {
+                let mut plugin = aparte.get_plugin_mut::<PresencePlugin>().unwrap();
+                let idle = Utc::now().signed_duration_since(plugin.last_activity).num_seconds().max(0) as u64;
+                match Self::idle_show(config, &plugin.show, idle) {
+                    Some(show) if plugin.idle.as_ref() != Some(&show) => {
+                        plugin.idle = Some(show);
+                        true
+                    },
+                    _ => false,
+                }
+            },
+            None => false,
+        };
+
+        if changed {
+            for account in aparte.accounts() {
+                Self::broadcast(&aparte, &account);
+            }
+        }
+    }
+
+    fn start_idle_timer(aparte: Rc<Aparte>) {
+        let timer = Interval::new(Instant::now() + IDLE_CHECK_INTERVAL, IDLE_CHECK_INTERVAL);
+        tokio::runtime::current_thread::spawn(timer.for_each(move |_| {
+            Self::check_idle(Rc::clone(&aparte));
+            Ok(())
+        }).map_err(|err| {
+            warn!("Idle timer error: {}", err);
+        }));
+    }
+
+    /// Change our status and broadcast it to the roster and joined channels of every account
+    pub fn set(aparte: Rc<Aparte>, show: Option<Show>, message: Option<String>) {
+        {
+            let mut plugin = aparte.get_plugin_mut::<PresencePlugin>().unwrap();
+            plugin.show = show;
+            plugin.message = message;
+            plugin.save();
+        }
+
+        for account in aparte.accounts() {
+            Self::broadcast(&aparte, &account);
+        }
+    }
+
+    fn broadcast(aparte: &Aparte, account: &FullJid) {
+        let presence = aparte.get_plugin::<PresencePlugin>().unwrap().presence(aparte, account);
+        aparte.send(account, presence.clone().into());
+
+        let channels = aparte.get_plugin::<crate::plugins::conversation::ConversationPlugin>().unwrap().channels(account);
+        for channel in channels {
+            let presence = presence.clone().with_to(channel);
+            aparte.send(account, presence.into());
+        }
+    }
+}
+
+impl Plugin for PresencePlugin {
+    fn new() -> PresencePlugin {
+        Self {
+            show: Some(Show::Chat),
+            message: None,
+            path: PathBuf::new(),
+            last_activity: Utc::now(),
+            idle: None,
+            idle_timer: false,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        self.path = aparte.data_dir.join("status.toml");
+        self.load();
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(account) => {
+                aparte.send(account, self.presence(&aparte, account).into());
+
+                if aparte.config.auto_away.is_some() && !self.idle_timer {
+                    self.idle_timer = true;
+                    Self::start_idle_timer(Rc::clone(&aparte));
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for PresencePlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Presence management")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_show_names_round_trip() {
+        for name in &["available", "away", "chat", "dnd", "xa"] {
+            let show = PresencePlugin::parse_show(name).unwrap();
+            assert_eq!(PresencePlugin::show_name(&show), *name);
+        }
+
+        assert!(PresencePlugin::parse_show("busy").is_err());
+    }
+
+    #[test]
+    fn test_idle_show() {
+        let config = AutoAway { away: 300, xa: Some(900), message: None };
+
+        assert_eq!(PresencePlugin::idle_show(&config, &None, 10), None);
+        assert_eq!(PresencePlugin::idle_show(&config, &None, 300), Some(Show::Away));
+        assert_eq!(PresencePlugin::idle_show(&config, &Some(Show::Chat), 1000), Some(Show::Xa));
+        assert_eq!(PresencePlugin::idle_show(&config, &Some(Show::Dnd), 1000), None);
+    }
+}
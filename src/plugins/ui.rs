@@ -2,13 +2,12 @@ use bytes::BytesMut;
 use chrono::Utc;
 use chrono::offset::{TimeZone, Local};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::fmt;
 use std::io::{Error as IoError, ErrorKind};
 use std::io::{Write, Stdout};
 use std::rc::Rc;
-use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use termion::color;
 use termion::event::Key;
@@ -18,12 +17,16 @@ use termion::screen::AlternateScreen;
 use tokio::codec::FramedRead;
 use tokio_codec::{Decoder};
 use uuid::Uuid;
-use xmpp_parsers::{BareJid, Jid};
+use xmpp_parsers::{BareJid, FullJid, Jid};
+use xmpp_parsers::data_forms::DataForm;
+use xmpp_parsers::disco::{DiscoInfoResult, DiscoItemsResult};
+use xmpp_parsers::roster::Subscription;
 
 use crate::core::{Plugin, Aparte, Event, CommandOrMessage};
-use crate::{contact, conversation};
+use crate::{contact, conversation, form};
 use crate::message::{Message, XmppMessage};
 use crate::command::{Command, CommandError};
+use crate::plugins::presence::PresencePlugin;
 use crate::terminus::{View, ViewTrait, Dimension, LinearLayout, FrameLayout, Input, Orientation, BufferedWin, Window, ListView};
 
 pub type CommandStream = FramedRead<tokio::reactor::PollEvented2<tokio_file_unix::File<std::fs::File>>, KeyCodec>;
@@ -36,12 +39,29 @@ enum UIEvent<'a> {
     Completed(String),
     ReadPassword,
     Connected(String),
-    Message(Message),
+    Disconnected(String),
+    /// Message to show in a given window
+    Message(String, Message),
+    MessageAcked(String),
+    MessageFailed(String),
+    Disco(Message),
     AddWindow(String, Option<Box<dyn ViewTrait<UIEvent<'a>> + 'a>>),
     ChangeWindow(String),
+    CloseWindow(String),
     Contact(contact::Contact),
     ContactUpdate(contact::Contact),
-    Occupant(conversation::Occupant),
+    ContactRemoved(contact::Contact),
+    SubscriptionRequest(contact::SubscriptionRequest),
+    SubscriptionRequestRemoved(contact::SubscriptionRequest),
+    Occupant(String, conversation::Occupant),
+    OccupantRemoved(String, conversation::Occupant),
+    Subject(String, Option<String>),
+    FormSelect(String, bool),
+    FormInput(String, String),
+    GetForm(String, Rc<RefCell<Option<DataForm>>>),
+    /// Whether the window of a conversation is scrolled to its oldest message
+    AtTop(String, Rc<RefCell<bool>>),
+    Notice(String, Message),
 }
 
 #[derive(Debug, Clone)]
@@ -52,12 +72,18 @@ enum ConversationKind {
 
 #[derive(Debug, Clone)]
 struct Conversation {
+    account: FullJid,
     jid: BareJid,
     kind: ConversationKind,
+    window: String,
 }
 
 struct TitleBar {
     window_name: Option<String>,
+    /// Status message of each contact, shown when its window is the current one
+    statuses: HashMap<String, String>,
+    /// Subject of each channel, shown when its window is the current one
+    subjects: HashMap<String, String>,
 }
 
 impl View<'_, TitleBar, UIEvent<'_>> {
@@ -77,6 +103,8 @@ impl View<'_, TitleBar, UIEvent<'_>> {
             cursor_y: None,
             content: TitleBar {
                 window_name: None,
+                statuses: HashMap::new(),
+                subjects: HashMap::new(),
             },
             event_handler: None,
         }
@@ -104,6 +132,12 @@ impl ViewTrait<UIEvent<'_>> for View<'_, TitleBar, UIEvent<'_>> {
             write!(screen, "{}", termion::cursor::Goto(self.x, self.y)).unwrap();
             if let Some(window_name) = &self.content.window_name {
                 write!(screen, " {}", window_name).unwrap();
+                if let Some(status) = self.content.statuses.get(window_name) {
+                    write!(screen, " - {}", status).unwrap();
+                }
+                if let Some(subject) = self.content.subjects.get(window_name) {
+                    write!(screen, " - {}", subject).unwrap();
+                }
             }
 
             write!(screen, "{}{}", color::Bg(color::Reset), color::Fg(color::Reset)).unwrap();
@@ -118,13 +152,167 @@ impl ViewTrait<UIEvent<'_>> for View<'_, TitleBar, UIEvent<'_>> {
             UIEvent::ChangeWindow(name) => {
                 self.set_name(name);
             },
+            UIEvent::Contact(contact) | UIEvent::ContactUpdate(contact) => {
+                let jid = contact.jid.to_string();
+                match &contact.status {
+                    Some(status) => self.content.statuses.insert(jid.clone(), status.clone()),
+                    None => self.content.statuses.remove(&jid),
+                };
+                if self.content.window_name.as_ref() == Some(&jid) {
+                    self.redraw();
+                }
+            },
+            UIEvent::CloseWindow(name) => {
+                self.content.subjects.remove(name);
+            },
+            UIEvent::Subject(window, subject) => {
+                match subject {
+                    Some(subject) => self.content.subjects.insert(window.clone(), subject.clone()),
+                    None => self.content.subjects.remove(window),
+                };
+                if self.content.window_name.as_ref() == Some(window) {
+                    self.redraw();
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Data form being filled, one field at a time
+struct FormWin {
+    window: String,
+    form: DataForm,
+    selected: usize,
+    error: Option<String>,
+}
+
+impl View<'_, FormWin, UIEvent<'_>> {
+    fn new(screen: Rc<RefCell<Screen>>, window: String, form: DataForm) -> Self {
+        let selected = form.fields.iter().position(form::editable).unwrap_or(0);
+
+        Self {
+            screen: screen,
+            width: Dimension::MatchParent,
+            height: Dimension::MatchParent,
+            x: 0,
+            y: 0,
+            w: None,
+            h: None,
+            dirty: true,
+            #[cfg(feature = "no-cursor-save")]
+            cursor_x: None,
+            #[cfg(feature = "no-cursor-save")]
+            cursor_y: None,
+            content: FormWin {
+                window: window,
+                form: form,
+                selected: selected,
+                error: None,
+            },
+            event_handler: None,
+        }
+    }
+
+    fn select(&mut self, next: bool) {
+        let fields = &self.content.form.fields;
+        let selected = self.content.selected;
+        let candidate = match next {
+            true => fields.iter().enumerate().skip(selected + 1).find(|(_, field)| form::editable(field)),
+            false => fields.iter().enumerate().take(selected).rev().find(|(_, field)| form::editable(field)),
+        };
+
+        if let Some((index, _)) = candidate {
+            self.content.selected = index;
+            self.redraw();
+        }
+    }
+
+    fn input(&mut self, input: &str) {
+        self.content.error = match self.content.form.fields.get_mut(self.content.selected) {
+            Some(field) => form::apply_input(field, input).err(),
+            None => None,
+        };
+        self.redraw();
+    }
+}
+
+impl ViewTrait<UIEvent<'_>> for View<'_, FormWin, UIEvent<'_>> {
+    fn redraw(&mut self) {
+        self.save_cursor();
+
+        {
+            let mut screen = self.screen.borrow_mut();
+            let (w, h) = (self.w.unwrap(), self.h.unwrap());
+
+            for y in self.y .. self.y + h {
+                write!(screen, "{}", termion::cursor::Goto(self.x, y)).unwrap();
+                for _ in 0 .. w {
+                    write!(screen, " ").unwrap();
+                }
+            }
+
+            let mut header = Vec::new();
+            if let Some(title) = &self.content.form.title {
+                header.push(format!("{}{}{}", color::Fg(color::Yellow), title, color::Fg(color::White)));
+            }
+            if let Some(instructions) = &self.content.form.instructions {
+                header.push(instructions.clone());
+            }
+            header.push(String::from("Up/Down select a field, Enter sets its value (empty toggles), /roomconfig submit or cancel"));
+            match &self.content.error {
+                Some(error) => header.push(format!("{}{}{}", color::Fg(color::Red), error, color::Fg(color::White))),
+                None => header.push(String::new()),
+            }
+
+            // Scroll so that the selected field stays visible
+            let lines = (h as usize).saturating_sub(header.len());
+            let visible = self.content.form.fields.iter().enumerate().filter(|(_, field)| field.type_ != xmpp_parsers::data_forms::FieldType::Hidden).collect::<Vec<_>>();
+            let position = visible.iter().position(|(index, _)| *index == self.content.selected).unwrap_or(0);
+            let first = match position >= lines {
+                true => position + 1 - lines,
+                false => 0,
+            };
+
+            let mut y = self.y;
+            for line in header {
+                write!(screen, "{}{}", termion::cursor::Goto(self.x, y), line).unwrap();
+                y += 1;
+            }
+
+            for (index, field) in visible.into_iter().skip(first).take(lines) {
+                write!(screen, "{}", termion::cursor::Goto(self.x, y)).unwrap();
+                let label = field.label.clone().unwrap_or_else(|| field.var.clone());
+                if !form::editable(field) {
+                    write!(screen, "  {}{}{}", color::Fg(color::Yellow), field.values.join(" "), color::Fg(color::White)).unwrap();
+                } else if index == self.content.selected {
+                    write!(screen, "{}> {}: {}{}", color::Fg(color::Green), label, form::display_value(field), color::Fg(color::White)).unwrap();
+                } else {
+                    write!(screen, "  {}: {}", label, form::display_value(field)).unwrap();
+                }
+                y += 1;
+            }
+        }
+
+        self.restore_cursor();
+        self.screen.borrow_mut().flush().unwrap();
+    }
+
+    fn event(&mut self, event: &mut UIEvent) {
+        match event {
+            UIEvent::FormSelect(window, next) if window == &self.content.window => self.select(*next),
+            UIEvent::FormInput(window, input) if window == &self.content.window => self.input(input),
+            UIEvent::GetForm(window, result) if window == &self.content.window => {
+                result.borrow_mut().replace(self.content.form.clone());
+            },
             _ => {},
         }
     }
 }
 
 struct WinBar {
-    connection: Option<String>,
+    /// Accounts in the order they first connected, and whether they are currently connected
+    connections: Vec<(String, bool)>,
     windows: Vec<String>,
     current_window: Option<String>,
     highlighted: Vec<String>,
@@ -146,7 +334,7 @@ impl View<'_, WinBar, UIEvent<'_>> {
             #[cfg(feature = "no-cursor-save")]
             cursor_y: None,
             content: WinBar {
-                connection: None,
+                connections: Vec::new(),
                 windows: Vec::new(),
                 current_window: None,
                 highlighted: Vec::new(),
@@ -161,6 +349,12 @@ impl View<'_, WinBar, UIEvent<'_>> {
         self.redraw();
     }
 
+    fn remove_window(&mut self, window: &str) {
+        self.content.windows.retain(|w| w != window);
+        self.content.highlighted.retain(|w| w != window);
+        self.redraw();
+    }
+
     fn set_current_window(&mut self, window: &str) {
         self.content.current_window = Some(window.to_string());
         self.content.highlighted.drain_filter(|w| w == &window);
@@ -190,8 +384,11 @@ impl ViewTrait<UIEvent<'_>> for View<'_, WinBar, UIEvent<'_>> {
             }
 
             write!(screen, "{}", termion::cursor::Goto(self.x, self.y)).unwrap();
-            if let Some(connection) = &self.content.connection {
-                write!(screen, " {}", connection).unwrap();
+            for (connection, connected) in &self.content.connections {
+                match connected {
+                    true => write!(screen, " {}", connection).unwrap(),
+                    false => write!(screen, " {}{} (disconnected){}", connection, color::Fg(color::Red), color::Fg(color::White)).unwrap(),
+                }
             }
 
             let mut windows = String::new();
@@ -235,10 +432,22 @@ impl ViewTrait<UIEvent<'_>> for View<'_, WinBar, UIEvent<'_>> {
             UIEvent::AddWindow(name, _) => {
                 self.add_window(name);
             }
+            UIEvent::CloseWindow(name) => {
+                self.remove_window(name);
+            }
             UIEvent::Connected(jid) => {
-                self.content.connection = Some(jid.clone());
+                match self.content.connections.iter_mut().find(|(connection, _)| connection == jid) {
+                    Some((_, connected)) => *connected = true,
+                    None => self.content.connections.push((jid.clone(), true)),
+                }
                 self.redraw();
             }
+            UIEvent::Disconnected(jid) => {
+                if let Some((_, connected)) = self.content.connections.iter_mut().find(|(connection, _)| connection == jid) {
+                    *connected = false;
+                    self.redraw();
+                }
+            }
             _ => {},
         }
     }
@@ -274,7 +483,12 @@ impl fmt::Display for Message {
             },
             Message::Outgoing(XmppMessage::Chat(message)) => {
                 let timestamp = Local.from_utc_datetime(&message.timestamp.naive_local());
-                write!(f, "{} - {}me:{} {}", timestamp.format("%T"), color::Fg(color::Yellow), color::Fg(color::White), message.body)
+                let marker = match (message.acked, message.failed) {
+                    (true, _) => format!("{}✓{}", color::Fg(color::Green), color::Fg(color::White)),
+                    (false, true) => format!("{}✗{}", color::Fg(color::Red), color::Fg(color::White)),
+                    (false, false) => String::from("-"),
+                };
+                write!(f, "{} {} {}me:{} {}", timestamp.format("%T"), marker, color::Fg(color::Yellow), color::Fg(color::White), message.body)
             }
             Message::Incoming(XmppMessage::Groupchat(message)) => {
                 if let Jid::Full(from) = &message.from_full {
@@ -296,7 +510,12 @@ impl fmt::Display for Message {
             },
             Message::Outgoing(XmppMessage::Groupchat(message)) => {
                 let timestamp = Local.from_utc_datetime(&message.timestamp.naive_local());
-                write!(f, "{} - {}me:{} {}", timestamp.format("%T"), color::Fg(color::Yellow), color::Fg(color::White), message.body)
+                let marker = match (message.acked, message.failed) {
+                    (true, _) => format!("{}✓{}", color::Fg(color::Green), color::Fg(color::White)),
+                    (false, true) => format!("{}✗{}", color::Fg(color::Red), color::Fg(color::White)),
+                    (false, false) => String::from("-"),
+                };
+                write!(f, "{} {} {}me:{} {}", timestamp.format("%T"), marker, color::Fg(color::Yellow), color::Fg(color::White), message.body)
             }
         }
     }
@@ -316,8 +535,30 @@ impl fmt::Display for contact::Contact {
         };
 
         match &self.name {
-            Some(name) => write!(f, "{} ({}){}", name, self.jid, color::Fg(color::White)),
-            None => write!(f, "{}{}", self.jid, color::Fg(color::White)),
+            Some(name) => write!(f, "{} ({}){}", name, self.jid, color::Fg(color::White))?,
+            None => write!(f, "{}{}", self.jid, color::Fg(color::White))?,
+        }
+
+        if let Some(status) = &self.status {
+            write!(f, " - {}", status)?;
+        }
+
+        let subscription = match self.subscription {
+            Subscription::Both => "both",
+            Subscription::From => "from",
+            Subscription::To => "to",
+            Subscription::None | Subscription::Remove => "none",
+        };
+        write!(f, " [{}]", subscription)
+    }
+}
+
+impl fmt::Display for contact::SubscriptionRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let account: BareJid = self.account.clone().into();
+        match &self.status {
+            Some(status) => write!(f, "{}{}{} to {} ({})", color::Fg(color::Yellow), self.jid, color::Fg(color::White), account, status),
+            None => write!(f, "{}{}{} to {}", color::Fg(color::Yellow), self.jid, color::Fg(color::White), account),
         }
     }
 }
@@ -338,13 +579,57 @@ impl fmt::Display for conversation::Role {
     }
 }
 
+const DISCO_WINDOW: &str = "disco";
+const SUBSCRIPTIONS_WINDOW: &str = "subscriptions";
+
+/// Render a disco#info and disco#items result as text
+fn format_disco(account: &FullJid, jid: &Jid, info: &DiscoInfoResult, items: &Option<DiscoItemsResult>) -> String {
+    let mut text = match &info.node {
+        Some(node) => format!("{} ({})", jid, node),
+        None => jid.to_string(),
+    };
+    let account: BareJid = account.clone().into();
+    text.push_str(&format!(" as seen by {}", account));
+
+    text.push_str("\nIdentities:");
+    for identity in info.identities.iter() {
+        text.push_str(&format!("\n  {}/{}", identity.category, identity.type_));
+        if let Some(name) = &identity.name {
+            text.push_str(&format!(" {}", name));
+        }
+    }
+
+    text.push_str("\nFeatures:");
+    for feature in info.features.iter() {
+        text.push_str(&format!("\n  {}", feature.var));
+    }
+
+    if let Some(items) = items {
+        text.push_str("\nItems:");
+        for item in items.items.iter() {
+            text.push_str(&format!("\n  {}", item.jid));
+            if let Some(node) = &item.node {
+                text.push_str(&format!(" [{}]", node));
+            }
+            if let Some(name) = &item.name {
+                text.push_str(&format!(" {}", name));
+            }
+        }
+    }
+
+    text
+}
+
 pub struct UIPlugin<'a> {
     screen: Rc<RefCell<Screen>>,
     windows: Vec<String>,
     current_window: Option<String>,
-    conversations: HashMap<String, Conversation>,
+    /// Conversations with a window, by account and contact or channel
+    conversations: HashMap<(BareJid, BareJid), Conversation>,
+    /// Windows showing a channel configuration form, with the account and channel it belongs to
+    forms: HashMap<String, (FullJid, BareJid)>,
     root: Box<dyn ViewTrait<UIEvent<'a>> + 'a>,
-    password_command: Option<Command>,
+    password_commands: VecDeque<Command>,
     completion: Option<Vec<String>>,
     current_completion: usize,
     running: Rc<AtomicBool>,
@@ -367,28 +652,94 @@ impl<'a> UIPlugin<'a> {
         self.root.event(&mut event);
     }
 
-    fn add_conversation(&mut self, conversation: Conversation) {
+    fn load_history(conversation: &Conversation) -> Event {
+        Event::LoadHistory {
+            account: conversation.account.clone(),
+            conversation: conversation.jid.clone(),
+            groupchat: match conversation.kind {
+                ConversationKind::Chat => false,
+                ConversationKind::Group => true,
+            },
+        }
+    }
+
+    /// Older messages to fetch when the current window is scrolled to its oldest one
+    pub fn history_top(&mut self) -> Option<Event> {
+        let conversation = self.current_window.as_ref().and_then(|window| self.window_conversation(window))?.clone();
+        let result = Rc::new(RefCell::new(false));
+        self.root.event(&mut UIEvent::AtTop(conversation.window.clone(), Rc::clone(&result)));
+        let top = *result.borrow();
+
+        match top {
+            true => Some(Self::load_history(&conversation)),
+            false => None,
+        }
+    }
+
+    fn window_conversation(&self, window: &str) -> Option<&Conversation> {
+        self.conversations.values().find(|conversation| conversation.window == window)
+    }
+
+    /// Window of the conversation with a contact or channel on a given account
+    pub fn conversation_window(&self, account: &FullJid, jid: &BareJid) -> Option<String> {
+        self.conversations.get(&(account.clone().into(), jid.clone())).map(|conversation| conversation.window.clone())
+    }
+
+    /// Window of a conversation, opened if needed
+    fn open_conversation(&mut self, aparte: &Rc<Aparte>, account: &FullJid, jid: &BareJid, kind: ConversationKind) -> String {
+        if let Some(window) = self.conversation_window(account, jid) {
+            return window;
+        }
+
+        // The same contact or channel can be open on several accounts, tell their windows apart
+        let bare: BareJid = account.clone().into();
+        let window = match self.windows.contains(&jid.to_string()) {
+            false => jid.to_string(),
+            true => format!("{} ({})", jid, bare),
+        };
+        self.add_conversation(aparte, Conversation {
+            account: account.clone(),
+            jid: jid.clone(),
+            kind: kind,
+            window: window.clone(),
+        });
+
+        window
+    }
+
+    fn add_conversation(&mut self, aparte: &Rc<Aparte>, conversation: Conversation) {
+        Rc::clone(aparte).event(Self::load_history(&conversation));
+
         match conversation.kind {
             ConversationKind::Chat => {
-                let chat = View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_event(|view, event| {
+                let window = conversation.window.clone();
+                let chat = View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_event(move |view, event| {
                     match event {
-                        UIEvent::Message(Message::Incoming(XmppMessage::Chat(message))) => {
-                            // TODO check to == us
-                            view.recv_message(&Message::Incoming(XmppMessage::Chat(message.clone())), true);
+                        UIEvent::Message(target, message) if target == &window => view.recv_message(message, true),
+                        UIEvent::MessageAcked(id) => {
+                            if let Some(message) = view.content.buf.iter_mut().find(|message| message.id() == id) {
+                                message.ack();
+                                view.redraw();
+                            }
                         },
-                        UIEvent::Message(Message::Outgoing(XmppMessage::Chat(message))) => {
-                            // TODO check from == us
-                            view.recv_message(&Message::Outgoing(XmppMessage::Chat(message.clone())), true);
+                        UIEvent::MessageFailed(id) => {
+                            if let Some(message) = view.content.buf.iter_mut().find(|message| message.id() == id) {
+                                message.fail();
+                                view.redraw();
+                            }
                         },
                         UIEvent::Key(Key::PageUp) => view.page_up(),
                         UIEvent::Key(Key::PageDown) => view.page_down(),
+                        UIEvent::AtTop(target, result) if target == &window => {
+                            *result.borrow_mut() = view.at_top();
+                        },
                         _ => {},
                     }
                 });
 
-                self.windows.push(conversation.jid.to_string());
-                self.root.event(&mut UIEvent::AddWindow(conversation.jid.to_string(), Some(Box::new(chat))));
-                self.conversations.insert(conversation.jid.to_string(), conversation);
+                self.windows.push(conversation.window.clone());
+                self.root.event(&mut UIEvent::AddWindow(conversation.window.clone(), Some(Box::new(chat))));
+                self.conversations.insert((conversation.account.clone().into(), conversation.jid.clone()), conversation);
             },
             ConversationKind::Group => {
                 let mut layout = View::<LinearLayout::<UIEvent<'a>>, UIEvent<'a>>::new(self.screen.clone(), Orientation::Horizontal, Dimension::MatchParent, Dimension::MatchParent).with_event(|layout, event| {
@@ -396,45 +747,149 @@ impl<'a> UIPlugin<'a> {
                         child.event(event);
                     }
                 });
-                let chat = View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_event(|view, event| {
+                let window = conversation.window.clone();
+                let chat = View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_event(move |view, event| {
                     match event {
-                        UIEvent::Message(Message::Incoming(XmppMessage::Groupchat(message))) => {
-                            // TODO check to == us
-                            view.recv_message(&Message::Incoming(XmppMessage::Groupchat(message.clone())), true);
+                        UIEvent::Message(target, message) | UIEvent::Notice(target, message) if target == &window => {
+                            view.recv_message(message, true);
                         },
-                        UIEvent::Message(Message::Outgoing(XmppMessage::Groupchat(message))) => {
-                            // TODO check from == us
-                            view.recv_message(&Message::Outgoing(XmppMessage::Groupchat(message.clone())), true);
+                        UIEvent::MessageAcked(id) => {
+                            if let Some(message) = view.content.buf.iter_mut().find(|message| message.id() == id) {
+                                message.ack();
+                                view.redraw();
+                            }
+                        },
+                        UIEvent::MessageFailed(id) => {
+                            if let Some(message) = view.content.buf.iter_mut().find(|message| message.id() == id) {
+                                message.fail();
+                                view.redraw();
+                            }
                         },
                         UIEvent::Key(Key::PageUp) => view.page_up(),
                         UIEvent::Key(Key::PageDown) => view.page_down(),
+                        UIEvent::AtTop(target, result) if target == &window => {
+                            *result.borrow_mut() = view.at_top();
+                        },
                         _ => {},
                     }
                 });
                 layout.push(chat);
 
-                let roster = View::<ListView<conversation::Role, conversation::Occupant>, UIEvent<'a>>::new(self.screen.clone()).with_none_group().with_event(|view, event| {
+                let window = conversation.window.clone();
+                let roster = View::<ListView<conversation::Role, conversation::Occupant>, UIEvent<'a>>::new(self.screen.clone()).with_none_group().with_event(move |view, event| {
                     match event {
-                        UIEvent::Occupant(occupant) => {
+                        UIEvent::Occupant(target, occupant) if target == &window => {
+                            // Role may have changed
+                            view.remove(occupant);
                             view.insert(occupant.clone(), Some(occupant.role));
                         },
+                        UIEvent::OccupantRemoved(target, occupant) if target == &window => {
+                            view.remove(occupant);
+                        },
                         _ => {},
                     }
                 });
                 layout.push(roster);
 
-                self.windows.push(conversation.jid.to_string());
-                self.root.event(&mut UIEvent::AddWindow(conversation.jid.to_string(), Some(Box::new(layout))));
-                self.conversations.insert(conversation.jid.to_string(), conversation);
+                self.windows.push(conversation.window.clone());
+                self.root.event(&mut UIEvent::AddWindow(conversation.window.clone(), Some(Box::new(layout))));
+                self.conversations.insert((conversation.account.clone().into(), conversation.jid.clone()), conversation);
             }
         }
     }
 
+    fn add_disco_window(&mut self) {
+        let disco = View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_event(|view, event| {
+            match event {
+                UIEvent::Disco(message) => view.recv_message(message, true),
+                UIEvent::Key(Key::PageUp) => view.page_up(),
+                UIEvent::Key(Key::PageDown) => view.page_down(),
+                _ => {},
+            }
+        });
+
+        self.windows.push(DISCO_WINDOW.to_string());
+        self.root.event(&mut UIEvent::AddWindow(DISCO_WINDOW.to_string(), Some(Box::new(disco))));
+    }
+
+    fn add_subscriptions_window(&mut self) {
+        let requests = View::<ListView<contact::Group, contact::SubscriptionRequest>, UIEvent<'a>>::new(self.screen.clone()).with_none_group().with_event(|view, event| {
+            match event {
+                UIEvent::SubscriptionRequest(request) => {
+                    view.remove(request);
+                    view.insert(request.clone(), None);
+                },
+                UIEvent::SubscriptionRequestRemoved(request) => view.remove(request),
+                _ => {},
+            }
+        });
+
+        self.windows.push(SUBSCRIPTIONS_WINDOW.to_string());
+        self.root.event(&mut UIEvent::AddWindow(SUBSCRIPTIONS_WINDOW.to_string(), Some(Box::new(requests))));
+    }
+
+    fn add_form_window(&mut self, account: &FullJid, channel: &BareJid, form: DataForm) {
+        let window = format!("{} config", channel);
+        let view = View::<FormWin, UIEvent<'a>>::new(self.screen.clone(), window.clone(), form);
+
+        if !self.windows.contains(&window) {
+            self.windows.push(window.clone());
+        }
+        self.root.event(&mut UIEvent::AddWindow(window.clone(), Some(Box::new(view))));
+        self.forms.insert(window.clone(), (account.clone(), channel.clone()));
+        self.change_window(&window);
+    }
+
+    /// Window name, account and channel of the current window when it shows a form
+    pub fn current_form(&self) -> Option<(String, FullJid, BareJid)> {
+        let window = self.current_window.as_ref()?;
+        self.forms.get(window).map(|(account, channel)| (window.clone(), account.clone(), channel.clone()))
+    }
+
+    /// Stop editing the form of a window, returning it as filled by the user
+    pub fn take_form(&mut self, window: &str) -> Option<DataForm> {
+        self.forms.remove(window)?;
+        let result = Rc::new(RefCell::new(None));
+        self.root.event(&mut UIEvent::GetForm(window.to_string(), Rc::clone(&result)));
+        let form = result.borrow_mut().take();
+        form
+    }
+
     pub fn change_window(&mut self, window: &str) {
         self.root.event(&mut UIEvent::ChangeWindow(window.to_string()));
         self.current_window = Some(window.to_string());
     }
 
+    /// Remove a window, switching to the previous one if it is the current one
+    fn close_window(&mut self, aparte: &Rc<Aparte>, window: &str) {
+        let index = match self.windows.iter().position(|w| w == window) {
+            Some(index) => index,
+            None => return,
+        };
+
+        if self.current_window.as_ref().map(String::as_str) == Some(window) {
+            let next = match index {
+                0 => self.windows.get(1).cloned(),
+                index => self.windows.get(index - 1).cloned(),
+            };
+            match next {
+                Some(next) => self.change_window(&next),
+                None => self.current_window = None,
+            }
+        }
+
+        self.windows.remove(index);
+        if let Some(conversation) = self.window_conversation(window).cloned() {
+            self.conversations.remove(&(conversation.account.clone().into(), conversation.jid.clone()));
+            Rc::clone(aparte).event(Event::ConversationClosed {
+                account: conversation.account,
+                conversation: conversation.jid,
+            });
+        }
+        self.forms.remove(window);
+        self.root.event(&mut UIEvent::CloseWindow(window.to_string()));
+    }
+
     pub fn next_window(&mut self) {
         if let Some(current) = &self.current_window {
             let index = self.windows.iter().position(|e| e == current).unwrap();
@@ -489,6 +944,28 @@ impl<'a> UIPlugin<'a> {
     pub fn get_windows(&self) -> Vec<String> {
         self.windows.clone()
     }
+
+    pub fn current_window(&self) -> Option<String> {
+        self.current_window.clone()
+    }
+
+    /// Channel shown in the current window, if any
+    pub fn current_channel(&self) -> Option<BareJid> {
+        match self.current_window.as_ref().and_then(|window| self.window_conversation(window)) {
+            Some(Conversation { jid, kind: ConversationKind::Group, .. }) => Some(jid.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn current_account(&self) -> Option<FullJid> {
+        match &self.current_window {
+            Some(current_window) => match self.window_conversation(current_window) {
+                Some(conversation) => Some(conversation.account.clone()),
+                None => None,
+            },
+            None => None,
+        }
+    }
 }
 
 impl<'a> Plugin for UIPlugin<'a> {
@@ -518,6 +995,9 @@ impl<'a> Plugin for UIPlugin<'a> {
                     let view = view.take().unwrap();
                     frame.insert(name.to_string(), view);
                 },
+                UIEvent::CloseWindow(name) => {
+                    frame.remove(name);
+                },
                 event => {
                     for (_, child) in frame.content.children.iter_mut() {
                         child.event(event);
@@ -567,7 +1047,8 @@ impl<'a> Plugin for UIPlugin<'a> {
             windows: Vec::new(),
             current_window: None,
             conversations: HashMap::new(),
-            password_command: None,
+            forms: HashMap::new(),
+            password_commands: VecDeque::new(),
             completion: None,
             current_completion: 0,
             running: Rc::new(AtomicBool::new(true)),
@@ -592,9 +1073,7 @@ impl<'a> Plugin for UIPlugin<'a> {
         });
         console.push(View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_event(|view, event| {
             match event {
-                UIEvent::Message(Message::Log(message)) => {
-                    view.recv_message(&Message::Log(message.clone()), true);
-                },
+                UIEvent::Message(window, message) if window == "console" => view.recv_message(message, true),
                 UIEvent::Key(Key::PageUp) => view.page_up(),
                 UIEvent::Key(Key::PageDown) => view.page_down(),
                 _ => {},
@@ -603,6 +1082,8 @@ impl<'a> Plugin for UIPlugin<'a> {
         let roster = View::<ListView<contact::Group, contact::Contact>, UIEvent<'a>>::new(self.screen.clone()).with_none_group().with_event(|view, event| {
             match event {
                 UIEvent::Contact(contact) | UIEvent::ContactUpdate(contact) => {
+                    // Groups may have changed
+                    view.remove(contact);
                     if contact.groups.len() > 0 {
                         for group in &contact.groups {
                             view.insert(contact.clone(), Some(group.clone()));
@@ -611,6 +1092,7 @@ impl<'a> Plugin for UIPlugin<'a> {
                             view.insert(contact.clone(), None);
                     }
                 }
+                UIEvent::ContactRemoved(contact) => view.remove(contact),
                 _ => {},
             }
         });
@@ -626,75 +1108,47 @@ impl<'a> Plugin for UIPlugin<'a> {
     fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
         match event {
             Event::ReadPassword(command) => {
-                self.password_command = Some(command.clone());
-                self.root.event(&mut UIEvent::ReadPassword);
-            },
-            Event::Connected(jid) => {
-                self.root.event(&mut UIEvent::Connected(jid.to_string()));
-            },
-            Event::Message(message) => {
-                match message {
-                    Message::Incoming(XmppMessage::Chat(message)) => {
-                        let window_name = message.from.to_string();
-                        if !self.conversations.contains_key(&window_name) {
-                            self.add_conversation(Conversation {
-                                jid: BareJid::from_str(&window_name).unwrap(),
-                                kind: ConversationKind::Chat,
-                            });
-                        }
-                    },
-                    Message::Outgoing(XmppMessage::Chat(message)) => {
-                        let window_name = message.to.to_string();
-                        if !self.conversations.contains_key(&window_name) {
-                            self.add_conversation(Conversation {
-                                jid: BareJid::from_str(&window_name).unwrap(),
-                                kind: ConversationKind::Chat,
-                            });
-                        }
-                    },
-                    Message::Incoming(XmppMessage::Groupchat(message)) => {
-                        let window_name = message.from.to_string();
-                        if !self.conversations.contains_key(&window_name) {
-                            self.add_conversation(Conversation {
-                                jid: BareJid::from_str(&window_name).unwrap(),
-                                kind: ConversationKind::Group,
-                            });
-                        }
-                    },
-                    Message::Outgoing(XmppMessage::Groupchat(message)) => {
-                        let window_name = message.to.to_string();
-                        if !self.conversations.contains_key(&window_name) {
-                            self.add_conversation(Conversation {
-                                jid: BareJid::from_str(&window_name).unwrap(),
-                                kind: ConversationKind::Group,
-                            });
-                        }
-                    }
-                    Message::Log(_message) => {}
+                // Prompt one password at a time, the next one is asked once this one is validated
+                self.password_commands.push_back(command.clone());
+                if self.password_commands.len() == 1 {
+                    self.root.event(&mut UIEvent::ReadPassword);
+                }
+            },
+            // The bar shows accounts, whatever resource they are bound to
+            Event::Connected(jid) | Event::Resumed(jid) => {
+                self.root.event(&mut UIEvent::Connected(BareJid::from(Jid::Full(jid.clone())).to_string()));
+            },
+            Event::MessageAcked(_account, id) => {
+                self.root.event(&mut UIEvent::MessageAcked(id.clone()));
+            },
+            Event::MessageFailed(_account, id) => {
+                self.root.event(&mut UIEvent::MessageFailed(id.clone()));
+            },
+            Event::Disconnected(jid) => {
+                self.root.event(&mut UIEvent::Disconnected(BareJid::from(Jid::Full(jid.clone())).to_string()));
+            },
+            Event::Message(account, message) => {
+                let conversation = match (account, message) {
+                    (Some(account), Message::Incoming(XmppMessage::Chat(message))) => Some((account, &message.from, ConversationKind::Chat)),
+                    (Some(account), Message::Outgoing(XmppMessage::Chat(message))) => Some((account, &message.to, ConversationKind::Chat)),
+                    (Some(account), Message::Incoming(XmppMessage::Groupchat(message))) => Some((account, &message.from, ConversationKind::Group)),
+                    (Some(account), Message::Outgoing(XmppMessage::Groupchat(message))) => Some((account, &message.to, ConversationKind::Group)),
+                    _ => None,
+                };
+                let window = match conversation {
+                    Some((account, jid, kind)) => self.open_conversation(&aparte, account, jid, kind),
+                    None => "console".to_string(),
                 };
 
-                self.root.event(&mut UIEvent::Message(message.clone()));
+                self.root.event(&mut UIEvent::Message(window, message.clone()));
             },
-            Event::Chat(jid) => {
-                let win_name = jid.to_string();
-                if !self.conversations.contains_key(&win_name) {
-                    self.add_conversation(Conversation {
-                        jid: BareJid::from_str(&win_name).unwrap(),
-                        kind: ConversationKind::Chat,
-                    });
-                }
-                self.change_window(&win_name);
-            },
-            Event::Join(jid) => {
-                let bare: BareJid = jid.clone().into();
-                let win_name = bare.to_string();
-                if !self.conversations.contains_key(&win_name) {
-                    self.add_conversation(Conversation {
-                        jid: BareJid::from_str(&win_name).unwrap(),
-                        kind: ConversationKind::Group,
-                    });
-                }
-                self.change_window(&win_name);
+            Event::Chat { account, contact } => {
+                let window = self.open_conversation(&aparte, account, contact, ConversationKind::Chat);
+                self.change_window(&window);
+            },
+            Event::Join { account, channel, .. } => {
+                let window = self.open_conversation(&aparte, account, &channel.clone().into(), ConversationKind::Group);
+                self.change_window(&window);
             },
             Event::Win(window) => {
                 if self.windows.contains(window) {
@@ -703,14 +1157,58 @@ impl<'a> Plugin for UIPlugin<'a> {
                     aparte.log(format!("Unknown window {}", window));
                 }
             },
-            Event::Contact(contact) => {
+            Event::Close(window) => {
+                self.close_window(&aparte, window);
+            },
+            Event::Contact(_account, contact) => {
                 self.root.event(&mut UIEvent::Contact(contact.clone()));
             },
-            Event::ContactUpdate(contact) => {
+            Event::ContactUpdate(_account, contact) => {
                 self.root.event(&mut UIEvent::ContactUpdate(contact.clone()));
             },
-            Event::Occupant(occupant) => {
-                self.root.event(&mut UIEvent::Occupant(occupant.clone()));
+            Event::Disco { account, jid, info, items } => {
+                if !self.windows.iter().any(|window| window == DISCO_WINDOW) {
+                    self.add_disco_window();
+                }
+
+                self.change_window(DISCO_WINDOW);
+                self.root.event(&mut UIEvent::Disco(Message::log(format_disco(account, jid, info, items))));
+            },
+            Event::ContactRemoved(_account, contact) => {
+                self.root.event(&mut UIEvent::ContactRemoved(contact.clone()));
+            },
+            Event::SubscriptionRequest(_account, request) => {
+                if !self.windows.iter().any(|window| window == SUBSCRIPTIONS_WINDOW) {
+                    self.add_subscriptions_window();
+                }
+
+                self.root.event(&mut UIEvent::SubscriptionRequest(request.clone()));
+            },
+            Event::SubscriptionRequestRemoved(_account, request) => {
+                self.root.event(&mut UIEvent::SubscriptionRequestRemoved(request.clone()));
+            },
+            Event::Occupant { account, conversation, occupant } => {
+                if let Some(window) = self.conversation_window(account, conversation) {
+                    self.root.event(&mut UIEvent::Occupant(window, occupant.clone()));
+                }
+            },
+            Event::Subject { account, channel, subject, .. } => {
+                if let Some(window) = self.conversation_window(account, channel) {
+                    self.root.event(&mut UIEvent::Subject(window, subject.clone()));
+                }
+            },
+            Event::RoomConfig { account, channel, form } => {
+                self.add_form_window(account, channel, form.clone());
+            },
+            Event::OccupantRemoved { account, conversation, occupant } => {
+                if let Some(window) = self.conversation_window(account, conversation) {
+                    self.root.event(&mut UIEvent::OccupantRemoved(window, occupant.clone()));
+                }
+            },
+            Event::Notice { account, conversation, text } => {
+                if let Some(window) = self.conversation_window(account, conversation) {
+                    self.root.event(&mut UIEvent::Notice(window, Message::log(text.clone())));
+                }
             },
             Event::Signal(signal_hook::SIGWINCH) => {
                 let (width, height) = termion::terminal_size().unwrap();
@@ -754,6 +1252,10 @@ impl Decoder for KeyCodec {
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         if self.running.load(Ordering::Relaxed) {
+            if !buf.is_empty() {
+                PresencePlugin::key_pressed(Rc::clone(&self.aparte));
+            }
+
             let mut keys = buf.keys();
             while let Some(key) = keys.next() {
                 match key {
@@ -783,15 +1285,27 @@ impl Decoder for KeyCodec {
                     },
                     Ok(Key::Up) => {
                         let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
-                        ui.event(UIEvent::Key(Key::Up));
+                        match ui.current_form() {
+                            Some((window, _, _)) => ui.event(UIEvent::FormSelect(window, false)),
+                            None => ui.event(UIEvent::Key(Key::Up)),
+                        }
                     },
                     Ok(Key::Down) => {
                         let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
-                        ui.event(UIEvent::Key(Key::Down));
+                        match ui.current_form() {
+                            Some((window, _, _)) => ui.event(UIEvent::FormSelect(window, true)),
+                            None => ui.event(UIEvent::Key(Key::Down)),
+                        }
                     },
                     Ok(Key::PageUp) => {
-                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
-                        ui.event(UIEvent::Key(Key::PageUp));
+                        let history = {
+                            let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                            ui.event(UIEvent::Key(Key::PageUp));
+                            ui.history_top()
+                        };
+                        if let Some(event) = history {
+                            Rc::clone(&self.aparte).event(event);
+                        }
                     },
                     Ok(Key::PageDown) => {
                         let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
@@ -857,9 +1371,12 @@ impl Decoder for KeyCodec {
                         let (raw_buf, password) = result.as_ref().unwrap();
                         let raw_buf = raw_buf.clone();
                         if *password {
-                            let mut command = ui.password_command.take().unwrap();
+                            let mut command = ui.password_commands.pop_front().unwrap();
                             command.args.push(raw_buf.clone());
                             self.queue.push(Ok(CommandOrMessage::Command(command)));
+                            if !ui.password_commands.is_empty() {
+                                ui.event(UIEvent::ReadPassword);
+                            }
                         } else if raw_buf.starts_with("/") {
                             match Command::try_from(&*raw_buf) {
                                 Ok(command) => {
@@ -867,10 +1384,12 @@ impl Decoder for KeyCodec {
                                 },
                                 Err(_) => self.queue.push(Err(CommandError::Parse)),
                             }
+                        } else if let Some((window, _, _)) = ui.current_form() {
+                            ui.event(UIEvent::FormInput(window, raw_buf));
                         } else if raw_buf.len() > 0 {
                             if let Some(current_window) = ui.current_window.clone() {
-                                if let Some(conversation) = ui.conversations.get(&current_window) {
-                                    let us = self.aparte.current_connection().unwrap().clone().into();
+                                if let Some(conversation) = ui.window_conversation(&current_window) {
+                                    let us = conversation.account.clone().into();
                                     match conversation.kind {
                                         ConversationKind::Chat => {
                                             let from: Jid = us;
@@ -878,7 +1397,7 @@ impl Decoder for KeyCodec {
                                             let id = Uuid::new_v4();
                                             let timestamp = Utc::now();
                                             let message = Message::outgoing_chat(id.to_string(), timestamp, &from, &to, &raw_buf);
-                                            self.queue.push(Ok(CommandOrMessage::Message(message)));
+                                            self.queue.push(Ok(CommandOrMessage::Message(conversation.account.clone(), message)));
                                         },
                                         ConversationKind::Group => {
                                             let from: Jid = us;
@@ -886,7 +1405,7 @@ impl Decoder for KeyCodec {
                                             let id = Uuid::new_v4();
                                             let timestamp = Utc::now();
                                             let message = Message::outgoing_groupchat(id.to_string(), timestamp, &from, &to, &raw_buf);
-                                            self.queue.push(Ok(CommandOrMessage::Message(message)));
+                                            self.queue.push(Ok(CommandOrMessage::Message(conversation.account.clone(), message)));
                                         },
                                     }
                                 }
@@ -227,6 +227,13 @@ impl<'a, K, E> View<'a, FrameLayout<'a, K, E>, E>
         widget.layout(self.y, self.x);
         self.content.children.insert(key, widget);
     }
+
+    pub fn remove(&mut self, key: &K) {
+        self.content.children.remove(key);
+        if self.content.current.as_ref() == Some(key) {
+            self.content.current = None;
+        }
+    }
 }
 
 impl<K, E> ViewTrait<E> for View<'_, FrameLayout<'_, K, E>, E>
@@ -720,13 +727,14 @@ impl<E> ViewTrait<E> for View<'_, Input, E> {
     }
 }
 
-pub trait BufferedMessage = fmt::Display + Hash + std::cmp::Eq + std::clone::Clone;
+pub trait BufferedMessage = fmt::Display + Hash + std::cmp::Eq + std::cmp::Ord + std::clone::Clone;
 
 pub trait Window<T: BufferedMessage, E>: ViewTrait<E> {
     fn recv_message(&mut self, message: &T, print: bool);
     fn send_message(&self);
     fn page_up(&mut self);
     fn page_down(&mut self);
+    fn at_top(&self) -> bool;
 }
 
 pub struct BufferedWin<T: BufferedMessage> {
@@ -775,8 +783,17 @@ impl<T: BufferedMessage, E> Window<T, E> for View<'_, BufferedWin<T>, E> {
             return;
         }
 
-        self.content.history.insert(message.clone(), self.content.buf.len());
-        self.content.buf.push(message.clone());
+        // Keep the buffer sorted, delayed and archived messages being older than the ones already shown
+        let index = match self.content.buf.iter().rposition(|existing| existing <= message) {
+            Some(index) => index + 1,
+            None => 0,
+        };
+        for position in self.content.history.values_mut().filter(|position| **position >= index) {
+            *position += 1;
+        }
+
+        self.content.history.insert(message.clone(), index);
+        self.content.buf.insert(index, message.clone());
 
         if print {
             self.redraw();
@@ -811,6 +828,14 @@ impl<T: BufferedMessage, E> Window<T, E> for View<'_, BufferedWin<T>, E> {
         self.redraw();
     }
 
+    /// Whether the oldest message is shown
+    fn at_top(&self) -> bool {
+        let count = self.content.buf.iter().map(|m| format!("{}", m).lines().count()).sum::<usize>();
+        let height = self.h.unwrap_or(0) as usize;
+
+        count <= height || self.content.view >= count - height
+    }
+
     fn send_message(&self) {
     }
 }
@@ -912,6 +937,15 @@ impl<'a, G: fmt::Display + Hash + std::cmp::Eq, V: fmt::Display + Hash + std::cm
         }
         self.dirty = true
     }
+
+    /// Remove an item from every group it belongs to, dropping groups left empty
+    pub fn remove(&mut self, item: &V) {
+        for items in self.content.items.values_mut() {
+            items.remove(item);
+        }
+        self.content.items.retain(|group, items| group.is_none() || !items.is_empty());
+        self.dirty = true
+    }
 }
 
 impl<G: fmt::Display + Hash + std::cmp::Eq, V: fmt::Display + Hash + std::cmp::Eq, E> ViewTrait<E> for View<'_, ListView<G, V>, E> {